@@ -0,0 +1,7 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate bulletinboard;
+
+fuzz_target!(|data: &[u8]| {
+	bulletinboard::fuzzing::handle_message(data);
+});
@@ -0,0 +1,162 @@
+use std::net::{SocketAddr,ToSocketAddrs};
+use std::time::Duration;
+use std::path::Path;
+use std::sync::Arc;
+use std::io;
+
+use tokio_core::reactor::Handle;
+
+use kademlia::{Kademlia, Config};
+use node::NodeId;
+use error::{Error, Result};
+use transport::Transport;
+
+/// Configures and creates a `Kademlia` node
+///
+/// ```ignore
+/// let kad = KademliaBuilder::new()
+///     .listen("[::]:0")
+///     .supernode("162.211.65.88:56789")
+///     .bootstrap(handle)?;
+/// ```
+pub struct KademliaBuilder {
+	listen_addr: Option<SocketAddr>,
+	transport:   Option<Arc<Transport>>,
+	supernodes:  Vec<SocketAddr>,
+	own_id:      Option<NodeId>,
+	config:      Config,
+}
+
+impl KademliaBuilder {
+	pub fn new() -> KademliaBuilder {
+		KademliaBuilder {
+			listen_addr: "[::]:0".parse().ok(),
+			transport:   None,
+			supernodes:  vec![],
+			own_id:      None,
+			config:      Config::default(),
+		}
+	}
+
+	/// Listen on this address (default: `[::]:0`)
+	pub fn listen<A: ToSocketAddrs>(mut self, addr: A) -> KademliaBuilder {
+		self.listen_addr = addr.to_socket_addrs().ok().and_then(|mut it| it.next());
+		self
+	}
+
+	/// Send and receive messages through this transport instead of
+	/// binding a UDP socket to the listen address
+	pub fn transport(mut self, transport: Arc<Transport>) -> KademliaBuilder {
+		self.transport = Some(transport);
+		self
+	}
+
+	/// Bootstrap using this node
+	pub fn supernode<A: ToSocketAddrs>(mut self, addr: A) -> KademliaBuilder {
+		match addr.to_socket_addrs() {
+			Ok(it) => self.supernodes.extend(it),
+			Err(e) => warn!("Ignoring supernode: {}", e),
+		}
+		self
+	}
+
+	/// Bootstrap using these nodes
+	pub fn supernodes<A: ToSocketAddrs>(self, addrs: Vec<A>) -> KademliaBuilder {
+		addrs.into_iter().fold(self, |this, addr| this.supernode(addr))
+	}
+
+	/// Use this NodeId instead of the one derived from our key. Nodes
+	/// verifying ids will not add us to their routing table.
+	pub fn node_id(mut self, own_id: NodeId) -> KademliaBuilder {
+		self.own_id = Some(own_id);
+		self
+	}
+
+	/// How long values are kept by the nodes storing them (default: 15min)
+	pub fn ttl(mut self, ttl: Duration) -> KademliaBuilder {
+		self.config.ttl = ttl;
+		self
+	}
+
+	/// Keep the values stored for other nodes in this directory, so they
+	/// survive a restart (default: in memory)
+	pub fn storage_dir<P: AsRef<Path>>(mut self, dir: P) -> KademliaBuilder {
+		self.config.storage_dir = Some(dir.as_ref().to_path_buf());
+		self
+	}
+
+	/// Speak KRPC to join the BitTorrent Mainline DHT instead of our
+	/// own protocol (default: false)
+	pub fn mainline(mut self, mainline: bool) -> KademliaBuilder {
+		self.config.mainline = mainline;
+		self
+	}
+
+	/// Only trust nodes whose NodeId is derived from the key they sign
+	/// their messages with (default: false). Mainline nodes do not sign
+	/// their messages.
+	pub fn verify_ids(mut self, verify_ids: bool) -> KademliaBuilder {
+		self.config.verify_ids = verify_ids;
+		self
+	}
+
+	/// Make creating NodeIds harder: SHA1(NodeId) must start with this
+	/// many zero bits (default: 0)
+	pub fn id_difficulty(mut self, difficulty: u32) -> KademliaBuilder {
+		self.config.id_difficulty = difficulty;
+		self
+	}
+
+	/// Look up nodes and values along this many disjoint paths, so a single
+	/// malicious node cannot hijack a lookup (default: 1)
+	pub fn lookup_paths(mut self, paths: usize) -> KademliaBuilder {
+		self.config.lookup_paths = paths;
+		self
+	}
+
+	/// Compress values if that makes them smaller, so more than
+	/// `MAX_VALUE_LEN` bytes of e.g. text fit into one value (default: false)
+	pub fn compress_values(mut self, compress: bool) -> KademliaBuilder {
+		self.config.compress_values = compress;
+		self
+	}
+
+	/// Refresh buckets that saw no node and no lookup for this long
+	/// (default: 15min)
+	pub fn refresh_interval(mut self, interval: Duration) -> KademliaBuilder {
+		self.config.refresh_interval = interval;
+		self
+	}
+
+	/// Create a node without joining the network (e.g. for a supernode)
+	pub fn create(self, handle: Handle) -> Result<Kademlia> {
+		let own_id = self.own_id;
+		self.create_with_id(handle, own_id)
+	}
+
+	/// Create a node and join the network via the supernodes
+	pub fn bootstrap(self, handle: Handle) -> Result<Kademlia> {
+		let own_id = self.own_id;
+		let supernodes = self.supernodes.clone();
+
+		let mut kad = try!(self.create_with_id(handle, None));
+		kad.join(supernodes, own_id);
+
+		Ok(kad)
+	}
+
+	fn create_with_id(self, handle: Handle, own_id: Option<NodeId>) -> Result<Kademlia> {
+		match self.transport {
+			Some(transport) => Kademlia::with_transport(handle, transport, own_id, self.config),
+			None => {
+				let addr = try!(self.listen_addr());
+				Kademlia::create(handle, addr, own_id, self.config)
+			}
+		}
+	}
+
+	fn listen_addr(&self) -> Result<SocketAddr> {
+		let err = io::Error::new(io::ErrorKind::AddrNotAvailable, "no valid listen address");
+		self.listen_addr.ok_or(Error::Io(err))
+	}
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use bincode::{serialize, deserialize, Bounded};
+
+use node::NodeId;
+use message::Value;
+use immutable;
+use kademlia::MAX_VALUE_LEN;
+
+/// A manifest with this many chunk keys still fits into `MAX_VALUE_LEN`
+pub const MAX_CHUNKS: usize = 96;
+pub const MAX_CHUNKED_VALUE_LEN: usize = MAX_CHUNKS * MAX_VALUE_LEN;
+
+const MAGIC: [u8; 4] = *b"BBCM";
+
+/// Describes a large value split into immutable chunks.
+///
+/// The manifest is stored under the user's key, each chunk under its hash.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Manifest {
+	magic:      [u8; 4],
+	pub len:    u64,
+	pub hash:   NodeId,
+	pub chunks: Vec<NodeId>,
+}
+
+impl Manifest {
+	pub fn encode(&self) -> Vec<u8> {
+		serialize(self, Bounded(MAX_VALUE_LEN as u64)).unwrap()
+	}
+
+	/// Returns None if `data` is not a manifest
+	pub fn decode(data: &[u8]) -> Option<Manifest> {
+		let manifest:Manifest = match deserialize(data) {
+			Ok(m) => m,
+			Err(_) => return None,
+		};
+
+		if manifest.magic != MAGIC || manifest.chunks.len() > MAX_CHUNKS {
+			return None;
+		}
+		Some(manifest)
+	}
+
+	/// Put the chunks back together and check the result against the
+	/// manifest. Returns None if a chunk is missing or does not match.
+	pub fn assemble(&self, mut chunks: HashMap<NodeId, Vec<u8>>) -> Option<Vec<u8>> {
+		let mut data = Vec::with_capacity(self.len as usize);
+
+		for key in self.chunks.iter() {
+			match chunks.remove(key) {
+				Some(ref chunk) if immutable::verify(key, chunk) => data.extend_from_slice(chunk),
+				_ => return None,
+			}
+		}
+
+		if data.len() as u64 != self.len || immutable::immutable_key(&data) != self.hash {
+			return None;
+		}
+		Some(data)
+	}
+}
+
+/// Split `data` into immutable chunks of at most `MAX_VALUE_LEN` bytes.
+/// Returns None if `data` exceeds `MAX_CHUNKED_VALUE_LEN`.
+pub fn split(data: &[u8]) -> Option<(Manifest, Vec<(NodeId, Value)>)> {
+	if data.len() > MAX_CHUNKED_VALUE_LEN {
+		return None;
+	}
+
+	let chunks:Vec<(NodeId, Value)> = data.chunks(MAX_VALUE_LEN)
+		.map(|c| immutable::new_value(c.to_vec()))
+		.collect();
+
+	let manifest = Manifest {
+		magic:  MAGIC,
+		len:    data.len() as u64,
+		hash:   immutable::immutable_key(data),
+		chunks: chunks.iter().map(|&(key, _)| key).collect(),
+	};
+
+	Some((manifest, chunks))
+}
+
+#[test]
+fn split_and_assemble() {
+	let data:Vec<u8> = (0..10*1024).map(|i| i as u8).collect();
+
+	let (manifest, chunks) = split(&data).unwrap();
+	assert_eq!(chunks.len(), 5);
+	assert_eq!(Manifest::decode(&manifest.encode()), Some(manifest.clone()));
+
+	let mut fetched:HashMap<NodeId, Vec<u8>> = chunks.iter()
+		.map(|&(key, ref v)| (key, v.data.clone()))
+		.collect();
+	assert_eq!(manifest.assemble(fetched.clone()), Some(data));
+
+	let key = manifest.chunks[2];
+	fetched.get_mut(&key).unwrap()[0] ^= 0xff;
+	assert_eq!(manifest.assemble(fetched.clone()), None);
+
+	fetched.remove(&key);
+	assert_eq!(manifest.assemble(fetched), None);
+}
+
+#[test]
+fn limits() {
+	let data = vec![0; MAX_CHUNKED_VALUE_LEN];
+	let (manifest, _) = split(&data).unwrap();
+	assert!(manifest.encode().len() <= MAX_VALUE_LEN);
+
+	assert!(split(&vec![0; MAX_CHUNKED_VALUE_LEN + 1]).is_none());
+	assert_eq!(Manifest::decode(&[1,2,3]), None);
+}
@@ -1,4 +1,6 @@
 use std::sync::{Arc, Mutex, Condvar};
+use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::sync::mpsc::Receiver;
 use std::thread::spawn;
 
@@ -13,15 +15,45 @@ pub struct ClosestNodesIter {
 	count: usize, // ask at least <count> nodes
 	processed_nodes: Arc<Mutex<Vec<Node>>>,
 	unprocessed_nodes: Arc<(Mutex<(Vec<Node>, usize)>, Condvar)>,
+	claimed_addrs: Arc<Mutex<HashSet<SocketAddr>>>, // shared by disjoint paths
 }
 
 impl ClosestNodesIter {
 	pub fn new(key: NodeId, count: usize, node_list: Vec<Node>) -> ClosestNodesIter {
+		let claimed_addrs = Arc::new(Mutex::new(HashSet::new()));
+		Self::with_claimed_addrs(key, count, node_list, claimed_addrs)
+	}
+
+	/// S/Kademlia's disjoint lookups: `paths` iterators that never yield
+	/// the same node, the initial nodes are split among them.
+	pub fn disjoint(key: NodeId, count: usize, node_list: Vec<Node>, paths: usize)
+		-> Vec<ClosestNodesIter>
+	{
+		let paths = paths.max(1);
+		let claimed_addrs = Arc::new(Mutex::new(HashSet::new()));
+
+		let mut node_list = node_list;
+		node_list.sort_by(asc_dist_order!(key));
+
+		(0..paths).map(|i| {
+			let nodes = node_list.iter().enumerate()
+				.filter(|&(j, _)| j % paths == i)
+				.map(|(_, n)| n.clone())
+				.collect();
+			Self::with_claimed_addrs(key, count, nodes, claimed_addrs.clone())
+		}).collect()
+	}
+
+	fn with_claimed_addrs(key: NodeId, count: usize, node_list: Vec<Node>,
+	                      claimed_addrs: Arc<Mutex<HashSet<SocketAddr>>>)
+		-> ClosestNodesIter
+	{
 		let this = ClosestNodesIter {
 			key:               Arc::new(key),
 			count:             count,
 			processed_nodes:   Arc::new(Mutex::new(vec![])),
 			unprocessed_nodes: Arc::new((Mutex::new((vec![], 0)), Condvar::new())),
+			claimed_addrs:     claimed_addrs,
 		};
 
 		this.add_nodes(node_list);
@@ -162,7 +194,12 @@ impl Iterator for ClosestNodesIter {
 			match unprocessed_nodes.pop() {
 				None => return None,
 				Some(node) => {
-					processed_nodes.push(node.clone());
+					let mut claimed_addrs = self.claimed_addrs.lock().unwrap();
+
+					if claimed_addrs.contains(&node.addr) {
+						// another path asked this node already
+						continue
+					}
 
 					if closest_dist.map(|dist| node.dist(key) >= dist).unwrap_or(false)
 					{
@@ -174,7 +211,9 @@ impl Iterator for ClosestNodesIter {
 						debug!("looking for a closer node");
 						continue
 					}
-					
+
+					claimed_addrs.insert(node.addr);
+					processed_nodes.push(node.clone());
 					return Some(node)
 				}
 			}
@@ -212,11 +251,11 @@ fn order() {
 
 		let mut iter = ClosestNodesIter::new(key, count, vec![node0xff.clone()]);
 
-		let node0x77 = Node::new("127.0.0.1:2134", [0x77; NODEID_BYTELEN]).unwrap();
+		let node0x77 = Node::new("127.0.0.1:2135", [0x77; NODEID_BYTELEN]).unwrap();
 		iter.clone().add_nodes(vec![node0x77.clone()]);
 		assert_eq!(iter.next(), Some(node0x77));
 
-		let node0x00 = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+		let node0x00 = Node::new("127.0.0.1:2136", [0x00; NODEID_BYTELEN]).unwrap();
 		iter.clone().add_nodes(vec![node0x00.clone()]);
 		assert_eq!(iter.next(), Some(node0x00));
 
@@ -227,3 +266,46 @@ fn order() {
 		assert_eq!(iter.next(), None);
 	}
 }
+
+#[test]
+fn disjoint() {
+	let key = [0; NODEID_BYTELEN];
+
+	let nodes:Vec<Node> = (1..5u16)
+		.map(|i| Node::new(("127.0.0.1", i), [i as u8; NODEID_BYTELEN]).unwrap())
+		.collect();
+
+	let mut paths = ClosestNodesIter::disjoint(key, 10, nodes.clone(), 2);
+	assert_eq!(paths.len(), 2);
+
+	// both paths learn about the same node, but only one asks it
+	let node = Node::new("127.0.0.1:5", [0x05; NODEID_BYTELEN]).unwrap();
+	paths[0].add_node(node.clone());
+	paths[1].add_node(node.clone());
+
+	let mut seen = vec![];
+	for path in paths.iter_mut() {
+		seen.extend(path.by_ref());
+	}
+
+	seen.sort_by(asc_dist_order!(key));
+	assert_eq!(seen, vec![nodes[0].clone(), nodes[1].clone(), nodes[2].clone(), nodes[3].clone(), node]);
+}
+
+#[test]
+fn disjoint_claimed() {
+	let key = [0; NODEID_BYTELEN];
+	let node = |i: u8| Node::new(("127.0.0.1", i as u16), [i; NODEID_BYTELEN]).unwrap();
+
+	let mut paths = ClosestNodesIter::disjoint(key, 2, vec![node(2), node(3)], 2);
+
+	// the first path asks the closest node, the second one skips it
+	paths[0].add_node(node(1));
+	paths[1].add_node(node(1));
+	assert_eq!(paths[0].next(), Some(node(1)));
+	assert_eq!(paths[1].next(), Some(node(3)));
+
+	// the skipped node does not count as asked by the second path
+	paths[1].add_node(node(4));
+	assert_eq!(paths[1].next(), Some(node(4)));
+}
@@ -0,0 +1,92 @@
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use message::Value;
+
+/// Nobody decompresses a value into more than this many bytes
+pub const MAX_DECOMPRESSED_LEN: usize = 64*1024;
+
+/// Compressed data starts with these bytes. The flag lives in the data, so
+/// nodes that do not compress store and forward such values like any other.
+const MAGIC: [u8; 4] = *b"\0BBZ";
+
+/// Wrap `data` as a value, DEFLATE-compressed if `compress` is set and that
+/// makes it smaller.
+///
+/// Data that starts with the marker of compressed data is always
+/// compressed, so `open()` returns the original data in any case.
+pub fn new_value(data: Vec<u8>, compress: bool) -> Value {
+	let must_compress = data.starts_with(&MAGIC);
+
+	if (compress || must_compress) && data.len() <= MAX_DECOMPRESSED_LEN {
+		let mut encoder = DeflateEncoder::new(MAGIC.to_vec(), Compression::Best);
+		let compressed = encoder.write_all(&data).and_then(|_| encoder.finish());
+
+		if let Ok(compressed) = compressed {
+			if must_compress || compressed.len() < data.len() {
+				return Value::new(compressed);
+			}
+		}
+	}
+
+	Value::new(data)
+}
+
+/// The data of `value`, decompressed if necessary.
+///
+/// Returns None if it does not inflate or inflates to more than
+/// `MAX_DECOMPRESSED_LEN` bytes.
+pub fn open(value: Value) -> Option<Vec<u8>> {
+	if !value.data.starts_with(&MAGIC) {
+		return Some(value.data);
+	}
+
+	let mut data = vec![];
+	let decoder = DeflateDecoder::new(&value.data[MAGIC.len()..]);
+
+	// read one byte more to tell a bomb from a value of exactly the limit
+	match decoder.take(MAX_DECOMPRESSED_LEN as u64 + 1).read_to_end(&mut data) {
+		Ok(len) if len <= MAX_DECOMPRESSED_LEN => Some(data),
+		_ => None,
+	}
+}
+
+#[test]
+fn compress() {
+	let record = b"{\"name\": \"bulletinboard\", \"tags\": [\"dht\", \"kademlia\"]}\n";
+	let text:Vec<u8> = (0..40).flat_map(|_| record.iter().cloned()).collect();
+
+	let value = new_value(text.clone(), true);
+	assert!(value.data.starts_with(&MAGIC));
+	assert!(value.data.len() < text.len());
+	assert_eq!(open(value), Some(text.clone()));
+
+	// incompressible data is kept as it is
+	let value = new_value(vec![1, 2, 3], true);
+	assert_eq!(value.data, vec![1, 2, 3]);
+	assert_eq!(open(value), Some(vec![1, 2, 3]));
+
+	assert_eq!(new_value(text.clone(), false).data, text);
+
+	// data looking compressed is never put as it is
+	let mut tricky = MAGIC.to_vec();
+	tricky.push(1);
+	let value = new_value(tricky.clone(), false);
+	assert!(value.data != tricky);
+	assert_eq!(open(value), Some(tricky));
+
+	let mut data = MAGIC.to_vec();
+	data.extend(vec![0xff; 100]);
+	assert_eq!(open(Value::new(data)), None);
+}
+
+#[test]
+fn bomb() {
+	let mut encoder = DeflateEncoder::new(MAGIC.to_vec(), Compression::Best);
+	encoder.write_all(&vec![0; MAX_DECOMPRESSED_LEN + 1]).unwrap();
+
+	assert_eq!(open(Value::new(encoder.finish().unwrap())), None);
+}
@@ -1,18 +1,42 @@
 use std::borrow::Cow;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
 
 use futures::prelude::*;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core,Handle};
 
-use dbus::{Connection,BusType,NameFlag,ConnectionItem,MessageItem};
+use dbus::{Connection,BusType,NameFlag,ConnectionItem,MessageItem,Message};
 use dbus::obj::{ObjectPath,Method,Argument,Interface};
-use dbus_tokio::{AConnection};
-use dbus_tokio::tree::{AFactory, ATree, ATreeServer};
 
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 
 use kademlia::Kademlia;
+use error::Error;
+use message::ErrorCode;
 use node::{NodeId, NODEID_BYTELEN};
+use sealed::AppKey;
+use utils::ignore;
+
+/// Values of these applications are sealed with their AppKey, see SetSecret()
+type AppKeys = Rc<RefCell<HashMap<String, AppKey>>>;
+
+/// D-Bus error name for `err`, so clients can tell why the nodes
+/// rejected a value
+fn error_name(err: &Error, default: &'static str) -> &'static str {
+	match *err {
+		Error::ValueTooLarge(_)
+		| Error::Rejected(ErrorCode::ValueTooLarge)      => "org.manuel.Intercom.ValueTooLarge",
+		Error::Rejected(ErrorCode::QuotaExceeded)      => "org.manuel.Intercom.QuotaExceeded",
+		Error::Rejected(ErrorCode::BadSignature)       => "org.manuel.Intercom.BadSignature",
+		Error::Rejected(ErrorCode::UnsupportedVersion) => "org.manuel.Intercom.UnsupportedVersion",
+		Error::Rejected(ErrorCode::RateLimited)        => "org.manuel.Intercom.RateLimited",
+		Error::Rejected(ErrorCode::InvalidToken)       => "org.manuel.Intercom.InvalidToken",
+		_ => default,
+	}
+}
 
 fn message_item_to_u64(item: MessageItem) -> Result<u64, (&'static str, String)> {
 	match item {
@@ -75,14 +99,36 @@ fn hash(app_id: String, data: &[u8]) -> NodeId {
 	hash
 }
 
-fn dht_get(kad: Kademlia, app_id: MessageItem, key: MessageItem)
+fn dht_set_secret(keys: AppKeys, app_id: MessageItem, secret: MessageItem)
+	-> Result<Vec<MessageItem>, (&'static str, String)>
+{
+	let app_id = try!(message_item_to_string(app_id));
+	let secret = try!(message_item_to_byte_vec(secret));
+
+	let mut keys = keys.borrow_mut();
+	if secret.is_empty() {
+		keys.remove(&app_id);
+	} else {
+		let app_key = AppKey::derive(&app_id, &secret);
+		keys.insert(app_id, app_key);
+	}
+
+	Ok(vec![])
+}
+
+fn dht_get(kad: Kademlia, keys: AppKeys, app_id: MessageItem, key: MessageItem)
 	-> Result<Vec<MessageItem>, (&'static str, String)> 
 {
 	let app_id = try!(message_item_to_string(app_id));
 	let key = try!(message_item_to_byte_vec(key));
-	let hash_key = hash(app_id, &key);
+	let hash_key = hash(app_id.clone(), &key);
+
+	let values = match keys.borrow().get(&app_id) {
+		None => kad.get(hash_key),
+		Some(app_key) => kad.get_sealed(app_key, hash_key),
+	};
 
-	let items:Vec<MessageItem> = kad.get(hash_key).into_iter()
+	let items:Vec<MessageItem> = values.into_iter()
 		.map(byte_vec_to_message_item)
 		.collect();
 
@@ -91,43 +137,99 @@ fn dht_get(kad: Kademlia, app_id: MessageItem, key: MessageItem)
 	Ok(vec![values])
 }
 
-fn dht_put(mut kad: Kademlia, app_id: MessageItem, key: MessageItem, value: MessageItem)
+fn dht_put(mut kad: Kademlia, keys: AppKeys, app_id: MessageItem, key: MessageItem, value: MessageItem)
 	-> Result<Vec<MessageItem>, (&'static str, String)>
 {
 	let app_id = try!(message_item_to_string(app_id));
 	let key   = try!(message_item_to_byte_vec(key));
 	let value = try!(message_item_to_byte_vec(value));
-	let hash_key = hash(app_id, &key);
+	let hash_key = hash(app_id.clone(), &key);
 
-	kad.put(hash_key, value)
-		.map(|_| vec![])
-		.map_err(|_| ("org.manuel.Intercom.PutFailed", "Put failed".to_string()))
+	let res = match keys.borrow().get(&app_id) {
+		None => kad.put(hash_key, value),
+		Some(app_key) => kad.put_sealed(app_key.clone(), hash_key, value),
+	};
+
+	res.map(|_| vec![])
+		.map_err(|e| (error_name(&e, "org.manuel.Intercom.PutFailed"), format!("Put failed: {}", e)))
 }
 
-fn dht_store(mut kad: Kademlia, app_id: MessageItem, key: MessageItem, value: MessageItem, lifetime: MessageItem)
+fn dht_store(mut kad: Kademlia, keys: AppKeys, app_id: MessageItem, key: MessageItem, value: MessageItem, lifetime: MessageItem)
 	-> Result<Vec<MessageItem>, (&'static str, String)>
 {
 	let app_id = try!(message_item_to_string(app_id));
 	let key   = try!(message_item_to_byte_vec(key));
 	let value = try!(message_item_to_byte_vec(value));
 	let lifetime = try!(message_item_to_u64(lifetime));
+	let hash_key = hash(app_id.clone(), &key);
+
+	let res = match keys.borrow().get(&app_id) {
+		None => kad.store(hash_key, value, lifetime),
+		Some(app_key) => kad.store_sealed(app_key.clone(), hash_key, value, lifetime),
+	};
+
+	res.map(|_| vec![])
+		.map_err(|e| (error_name(&e, "org.manuel.Intercom.StoreFailed"), format!("Store failed: {}", e)))
+}
+
+fn dht_remove(mut kad: Kademlia, app_id: MessageItem, key: MessageItem)
+	-> Result<Vec<MessageItem>, (&'static str, String)>
+{
+	let app_id = try!(message_item_to_string(app_id));
+	let key   = try!(message_item_to_byte_vec(key));
 	let hash_key = hash(app_id, &key);
 
-	kad.store(hash_key, value, lifetime)
+	kad.remove(hash_key)
 		.map(|_| vec![])
-		.map_err(|_| ("org.manuel.Intercom.StoreFailed", "Store failed".to_string()))
+		.map_err(|e| ("org.manuel.Intercom.RemoveFailed", format!("Remove failed: {}", e)))
+}
+
+/// Emit a `NewValue(app_id, key, value)` signal for every new value
+/// stored under `key`
+fn dht_watch(kad: Kademlia, keys: AppKeys, conn: Rc<Connection>, handle: Handle, app_id: MessageItem, key: MessageItem)
+	-> Result<Vec<MessageItem>, (&'static str, String)>
+{
+	let app_id = try!(message_item_to_string(app_id));
+	let key   = try!(message_item_to_byte_vec(key));
+	let hash_key = hash(app_id.clone(), &key);
+	let app_key = keys.borrow().get(&app_id).cloned();
+
+	let watch = kad.watch(hash_key).filter_map(move |value| {
+		match app_key {
+			None => Some(value),
+			Some(ref app_key) => app_key.open(&hash_key, &value),
+		}
+	}).for_each(move |value| {
+		let mut signal = Message::new_signal("/", "org.manuel.BulletinBoard", "NewValue").unwrap();
+		signal.append_items(&[
+			MessageItem::Str(app_id.clone()),
+			byte_vec_to_message_item(key.clone()),
+			byte_vec_to_message_item(value),
+		]);
+
+		ignore(conn.send(signal));
+		Ok(())
+	});
+	handle.spawn(watch);
+
+	Ok(vec![])
 }
 
-pub fn dbus(kad: Kademlia, dbus_name: &'static str) {
-	let c = Connection::get_private(BusType::Session).unwrap();
+/// Serve the D-Bus interface forever, turning `core` (the one `kad` runs
+/// on) in between method calls
+pub fn dbus(kad: Kademlia, dbus_name: &'static str, core: &mut Core) {
+	let c = Rc::new(Connection::get_private(BusType::Session).unwrap());
 	c.register_name(dbus_name, NameFlag::ReplaceExisting as u32).unwrap();
 
-	unimplemented!()/*
-	let f = AFactory::new_afn::<()>();
-	let tree = f.tree(ATree::new()).add(f.object_path("/", ()).introspectable().add(
-		f.interface("org.manuel.BulletinBoard", ()).add_m(
-			f.amethod("Get", (), move |m| {
-			}
+	let keys:AppKeys = Rc::new(RefCell::new(HashMap::new()));
+	let handle = core.handle();
+
+	let (kad_get, keys_get) = (kad.clone(), keys.clone());
+	let (kad_put, keys_put) = (kad.clone(), keys.clone());
+	let (kad_store, keys_store) = (kad.clone(), keys.clone());
+	let keys_secret = keys.clone();
+	let kad_remove = kad.clone();
+	let (kad_watch, keys_watch, conn_watch) = (kad.clone(), keys.clone(), c.clone());
 
 	let mut o = ObjectPath::new(&c, "/", true);
 	o.insert_interface("org.manuel.BulletinBoard", Interface::new(
@@ -135,31 +237,58 @@ pub fn dbus(kad: Kademlia, dbus_name: &'static str) {
 			Method::new("Get",
 				vec![Argument::new("app_id", "s"), Argument::new("key", "ay")],
 				vec![Argument::new("value", "aay")],
-				Box::new(|msg| {
+				Box::new(move |msg| {
 					let app_id = try!(msg.get_items().get(0).ok_or(("org.manuel.BulletinBoard.Invalid", "Invaild app_id".to_string()))).clone();
 					let key = try!(msg.get_items().get(1).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid key".to_string()))).clone();
-					dht_get(kad.clone(), app_id, key)
+					dht_get(kad_get.clone(), keys_get.clone(), app_id, key)
 				})
 			),
 			Method::new("Put",
 				vec![Argument::new("app_id", "s"), Argument::new("key", "ay"), Argument::new("value", "ay")],
 				vec![],
-				Box::new(|msg| {
+				Box::new(move |msg| {
 					let app_id = try!(msg.get_items().get(0).ok_or(("org.manuel.BulletinBoard.Invaild", "Invaild app_id".to_string()))).clone();
 					let key = try!(msg.get_items().get(1).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid key".to_string()))).clone();
 					let value = try!(msg.get_items().get(2).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid value".to_string()))).clone();
-					dht_put(kad.clone(), app_id, key, value)
+					dht_put(kad_put.clone(), keys_put.clone(), app_id, key, value)
 				})
 			),
 			Method::new("Store",
 				vec![Argument::new("app_id", "s"), Argument::new("key", "ay"), Argument::new("value", "ay"), Argument::new("lifetime", "t")],
 				vec![],
-				Box::new(|msg| {
+				Box::new(move |msg| {
 					let app_id = try!(msg.get_items().get(0).ok_or(("org.manuel.BulletinBoard.Invaild", "Invaild app_id".to_string()))).clone();
 					let key = try!(msg.get_items().get(1).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid key".to_string()))).clone();
 					let value = try!(msg.get_items().get(2).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid value".to_string()))).clone();
 					let lifetime = try!(msg.get_items().get(3).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid value".to_string()))).clone();
-					dht_store(kad.clone(), app_id, key, value, lifetime)
+					dht_store(kad_store.clone(), keys_store.clone(), app_id, key, value, lifetime)
+				})
+			),
+			Method::new("SetSecret",
+				vec![Argument::new("app_id", "s"), Argument::new("secret", "ay")],
+				vec![],
+				Box::new(move |msg| {
+					let app_id = try!(msg.get_items().get(0).ok_or(("org.manuel.BulletinBoard.Invaild", "Invaild app_id".to_string()))).clone();
+					let secret = try!(msg.get_items().get(1).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid secret".to_string()))).clone();
+					dht_set_secret(keys_secret.clone(), app_id, secret)
+				})
+			),
+			Method::new("Remove",
+				vec![Argument::new("app_id", "s"), Argument::new("key", "ay")],
+				vec![],
+				Box::new(move |msg| {
+					let app_id = try!(msg.get_items().get(0).ok_or(("org.manuel.BulletinBoard.Invaild", "Invaild app_id".to_string()))).clone();
+					let key = try!(msg.get_items().get(1).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid key".to_string()))).clone();
+					dht_remove(kad_remove.clone(), app_id, key)
+				})
+			),
+			Method::new("Watch",
+				vec![Argument::new("app_id", "s"), Argument::new("key", "ay")],
+				vec![],
+				Box::new(move |msg| {
+					let app_id = try!(msg.get_items().get(0).ok_or(("org.manuel.BulletinBoard.Invaild", "Invaild app_id".to_string()))).clone();
+					let key = try!(msg.get_items().get(1).ok_or(("org.manuel.BulletinBoard.Invaild", "Invalid key".to_string()))).clone();
+					dht_watch(kad_watch.clone(), keys_watch.clone(), conn_watch.clone(), handle.clone(), app_id, key)
 				})
 			),
 		],
@@ -168,12 +297,15 @@ pub fn dbus(kad: Kademlia, dbus_name: &'static str) {
 	));
 	o.set_registered(true).unwrap();
 
-	let mut core = Core::new().unwrap();
-	let aconn = AConnection::new(c.clone(), core.handle()).unwrap();
-	let server = ATreeServer::new(c.clone(), &tree, aconn.messages().unwrap());
+	for item in c.iter(10) {
+		if let ConnectionItem::MethodCall(mut m) = item {
+			if o.handle_message(&mut m).is_none() {
+				debug!("Unhandled message: {:?}", m);
+			}
+		}
 
-	let server = server.for_each(|m| { println!("Unhandled message: {:?}", m); Ok(()) });
-	core.run(server).unwrap();*/
+		core.turn(Some(Duration::from_millis(10)));
+	}
 }
 
 #[cfg(test)]
@@ -187,8 +319,6 @@ mod tests {
 	use kademlia::Kademlia;
 
 	use tokio_core::reactor::Core;
-	use tokio_core::reactor::Handle;
-	use tokio_core::reactor::Timeout;
 
 	use super::byte_vec_to_message_item;
 	use super::message_item_to_byte_vec;
@@ -200,23 +330,22 @@ mod tests {
 		let zeros = [0x00; NODEID_BYTELEN];
 		let ones = [0xFF; NODEID_BYTELEN];
 
-		let core = Core::new().unwrap();
-		let handle = core.handle();
-
-		let super_addr = ("127.0.0.1", 20000);
-		let _ = Kademlia::new_supernode(handle.clone(), super_addr, Some(zeros.clone()));
-
-		let kad = Kademlia::bootstrap(handle.clone(), "127.0.0.1:20001", vec![super_addr], Some(ones.clone()));
-
 		let dbus_name = "org.manuel.BulletinBoardTest1";
 		let name = dbus_name.clone();
-		handle.spawn_fn(move || {
-			super::dbus(kad, name);
 
-			Ok(())
+		// dbus() never returns, so the nodes live on their own thread
+		spawn(move || {
+			let mut core = Core::new().unwrap();
+			let handle = core.handle();
+
+			let super_addr = ("127.0.0.1", 20000);
+			let _ = Kademlia::new_supernode(handle.clone(), super_addr, Some(zeros.clone()));
+
+			let kad = Kademlia::bootstrap(handle.clone(), "127.0.0.1:20001", vec![super_addr], Some(ones.clone())).unwrap();
+			super::dbus(kad, name, &mut core);
 		});
 
-		core.run(Timeout::new(Duration::from_millis(500), &handle).unwrap()).unwrap();
+		sleep(Duration::from_millis(500));
 		dbus_put(dbus_name.clone(), &app_id, "foo".bytes().collect(), "bar".bytes().collect());
 		
 		let actual = dbus_get(dbus_name.clone(), &app_id, "foo".bytes().collect());
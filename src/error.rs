@@ -0,0 +1,70 @@
+use std::io;
+use std::fmt;
+use std::error;
+use std::result;
+
+use kademlia::MAX_VALUE_LEN;
+use mutable::MAX_SALT_LEN;
+use message::ErrorCode;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+	/// The value exceeds `MAX_VALUE_LEN` bytes
+	ValueTooLarge(usize),
+	/// The salt of a mutable value exceeds `MAX_SALT_LEN` bytes
+	SaltTooLarge(usize),
+	/// No nodes were reachable to store the value on
+	NoNodesFound,
+	/// Another version was stored first, this is its sequence number
+	Conflict(u64),
+	/// No node we asked accepted the request, this is why one rejected it
+	Rejected(ErrorCode),
+	Io(io::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::ValueTooLarge(len) =>
+				write!(f, "value has {} bytes but must not exceed {} bytes", len, MAX_VALUE_LEN),
+			Error::SaltTooLarge(len) =>
+				write!(f, "salt has {} bytes but must not exceed {} bytes", len, MAX_SALT_LEN),
+			Error::NoNodesFound =>
+				write!(f, "could not find any nodes"),
+			Error::Conflict(seq) =>
+				write!(f, "conflicting version {} is already stored", seq),
+			Error::Rejected(code) =>
+				write!(f, "rejected by all nodes: {}", code),
+			Error::Io(ref err) =>
+				write!(f, "{}", err),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn description(&self) -> &str {
+		match *self {
+			Error::ValueTooLarge(_) => "value too large",
+			Error::SaltTooLarge(_)  => "salt too large",
+			Error::NoNodesFound     => "no nodes found",
+			Error::Conflict(_)      => "conflicting version",
+			Error::Rejected(_)      => "rejected",
+			Error::Io(ref err)      => err.description(),
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			Error::Io(ref err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Error {
+		Error::Io(err)
+	}
+}
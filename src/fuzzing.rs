@@ -0,0 +1,36 @@
+//! Entry points of the cargo-fuzz targets in `fuzz/`
+
+use std::sync::Arc;
+
+use tokio_core::reactor::Core;
+
+use message::Message;
+use protocol;
+use builder::KademliaBuilder;
+use transport::SimulatedNetwork;
+
+/// `Message::decode()` must not panic and whatever it accepts must
+/// survive a roundtrip
+pub fn decode_message(data: &[u8]) {
+	if let Some(msg) = Message::decode(data) {
+		assert!(msg.is_valid());
+		assert_eq!(Message::decode(&msg.encode()), Some(msg));
+	}
+}
+
+/// Handling a datagram must not panic, whatever a peer sends
+pub fn handle_message(data: &[u8]) {
+	let envelope = match protocol::decode(data) {
+		Ok(envelope) => envelope,
+		Err(_) => return,
+	};
+
+	let core = Core::new().unwrap();
+	let net = SimulatedNetwork::new(0);
+
+	let mut kad = KademliaBuilder::new()
+		.transport(Arc::new(net.bind("10.0.0.1:1000".parse().unwrap()).unwrap()))
+		.create(core.handle()).unwrap();
+
+	let _ = kad.fuzz_handle_message("10.0.0.2:1000".parse().unwrap(), envelope.message);
+}
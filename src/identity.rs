@@ -0,0 +1,100 @@
+use std::fmt;
+
+use crypto::ed25519;
+use rand;
+
+use node::NodeId;
+use message::enc_id;
+use mutable::{PublicKey, SIGNATURE_BYTELEN};
+// SHA1, truncated to a NodeId
+use immutable::immutable_key as sha1;
+
+/// The keypair a node signs its messages with.
+///
+/// The NodeId is SHA1 of the public key (S/Kademlia), so nobody can claim
+/// an id without its secret key.
+#[derive(Clone)]
+pub struct Identity {
+	secret: Vec<u8>,
+	public: PublicKey,
+}
+
+impl Identity {
+	/// Generate keypairs until `node_id_of()` the public key has
+	/// `difficulty` leading zero bits in its hash.
+	///
+	/// Each bit of difficulty doubles the work to create an identity.
+	pub fn generate(difficulty: u32) -> Identity {
+		loop {
+			let seed:Vec<u8> = (0..32).map(|_| rand::random::<u8>()).collect();
+			let (secret, public) = ed25519::keypair(&seed);
+
+			if solves_puzzle(&node_id_of(&public), difficulty) {
+				return Identity {
+					secret: secret.to_vec(),
+					public: public,
+				};
+			}
+		}
+	}
+
+	pub fn public_key(&self) -> PublicKey {
+		self.public
+	}
+
+	pub fn node_id(&self) -> NodeId {
+		node_id_of(&self.public)
+	}
+
+	pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+		ed25519::signature(data, &self.secret).to_vec()
+	}
+}
+
+impl fmt::Debug for Identity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Identity {{ id={} }}", enc_id(&self.node_id()))
+	}
+}
+
+pub fn node_id_of(public_key: &PublicKey) -> NodeId {
+	sha1(public_key)
+}
+
+pub fn verify(public_key: &PublicKey, data: &[u8], sig: &[u8]) -> bool {
+	sig.len() == SIGNATURE_BYTELEN && ed25519::verify(data, public_key, sig)
+}
+
+/// S/Kademlia's static puzzle: SHA1(node_id) starts with `difficulty`
+/// zero bits
+pub fn solves_puzzle(node_id: &NodeId, difficulty: u32) -> bool {
+	leading_zero_bits(&sha1(node_id)) >= difficulty
+}
+
+fn leading_zero_bits(id: &NodeId) -> u32 {
+	let mut bits = 0;
+	for b in id.iter() {
+		bits += b.leading_zeros();
+		if *b != 0 {
+			break;
+		}
+	}
+	bits
+}
+
+#[test]
+fn sign_and_verify() {
+	let identity = Identity::generate(4);
+	assert!(solves_puzzle(&identity.node_id(), 4));
+	assert_eq!(identity.node_id(), node_id_of(&identity.public_key()));
+
+	let sig = identity.sign(b"hello");
+	assert!(verify(&identity.public_key(), b"hello", &sig));
+	assert!(!verify(&identity.public_key(), b"hullo", &sig));
+	assert!(!verify(&Identity::generate(0).public_key(), b"hello", &sig));
+	assert!(!verify(&identity.public_key(), b"hello", &sig[1..]));
+
+	assert_eq!(leading_zero_bits(&[0xff; 20]), 0);
+	assert_eq!(leading_zero_bits(&[0x00, 0x01, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), 15);
+	assert_eq!(leading_zero_bits(&[0x00; 20]), 160);
+}
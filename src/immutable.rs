@@ -0,0 +1,47 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use node::{NodeId, NODEID_BYTELEN};
+use message::Value;
+
+/// The DHT key of an immutable value is SHA1(value)
+pub fn immutable_key(data: &[u8]) -> NodeId {
+	let mut hasher = Sha1::new();
+
+	let mut output = vec![0x0; hasher.output_bytes()];
+	hasher.input(data);
+	hasher.result(&mut output[..]);
+
+	let mut key = [0u8; NODEID_BYTELEN];
+	key.clone_from_slice(&output[..NODEID_BYTELEN]);
+	key
+}
+
+/// Wrap `data` as an immutable value, returns its key as well
+pub fn new_value(data: Vec<u8>) -> (NodeId, Value) {
+	let key = immutable_key(&data);
+
+	let value = Value {
+		data:      data,
+		signature: None,
+		immutable: true,
+	};
+
+	(key, value)
+}
+
+/// Check that an immutable value is stored under its own hash
+pub fn verify(key: &NodeId, data: &[u8]) -> bool {
+	immutable_key(data) == *key
+}
+
+#[test]
+fn content_addressed() {
+	let (key, value) = new_value(vec![1,2,3]);
+	assert!(value.verify(&key));
+	assert!(!value.verify(&[0x00; NODEID_BYTELEN]));
+
+	let mut forged = value.clone();
+	forged.data = vec![4,5,6];
+	assert!(!forged.verify(&key));
+}
@@ -1,31 +1,96 @@
 use std::io;
 use std::sync::mpsc;
 use std::thread::{spawn,sleep};
-use std::net::{UdpSocket,SocketAddr,ToSocketAddrs};
+use std::net::{UdpSocket,SocketAddr,ToSocketAddrs,Ipv4Addr,IpAddr};
 use std::sync::{Arc,Mutex,RwLock};
 use std::collections::{HashMap, HashSet};
 use std::time::Duration;
+use std::path::PathBuf;
 
 use futures::Future;
 use futures::Stream;
+use futures::future::join_all;
 use tokio_core::reactor::Handle;
 use tokio_core::reactor::Interval;
 use futures_timer::Sleep;
 
 use storage;
 use server::Server;
+use transport::Transport;
+use builder::KademliaBuilder;
+use error::{Error, Result};
 use kbuckets::KBuckets;
-use node::{Node, NodeId};
+use node::{self, Node, NodeId};
 use closest_nodes_iter::ClosestNodesIter;
-use message::{Message,Value,Cookie,COOKIE_BYTELEN};
-use message::{Ping,Pong, FindNode, FoundNode, FindValue, FoundValue, Store};
+use message::{self, Message, Value, Cookie, ErrorCode, COOKIE_BYTELEN};
+use message::{Ping,Pong, FindNode, FoundNode, FindValue, FoundValue, Store, Stored, Conflict, Remove, Listen};
 use utils::ignore;
 use message::enc_id;
+use mutable::{self, Keypair, PublicKey, MAX_SALT_LEN};
+use immutable;
+use watch::Watch;
+use chunked::{self, Manifest};
+use compressed;
+use sealed::AppKey;
+use protocol::{self, Codec, CAP_LISTEN, CAP_REMOVE};
+use krpc::Krpc;
+use token::{Token, WriteTokens, PeerTokens};
+use identity::{self, Identity};
 
 pub const K_PARAM: usize = 20;
 pub const ALPHA_PARAM: isize = 3;
 pub const TIMEOUT_MS: u32 = 2000;
 pub const MAX_VALUE_LEN: usize = 2048;
+/// Most values one `FoundValue` carries
+pub const MAX_FOUND_VALUES: usize = 64;
+
+#[derive(Clone)]
+pub struct Config {
+	/// How long values are kept by the nodes storing them
+	pub ttl: Duration,
+	/// Keep the values we store for others in this directory instead of memory
+	pub storage_dir: Option<PathBuf>,
+	/// Speak KRPC to join the BitTorrent Mainline DHT, see `krpc`
+	pub mainline: bool,
+	/// Only add nodes to the routing table that signed their messages with
+	/// the key their NodeId is derived from, see `identity`
+	pub verify_ids: bool,
+	/// Leading zero bits of SHA1(NodeId) our id has and, with `verify_ids`,
+	/// other nodes' ids must have
+	pub id_difficulty: u32,
+	/// Number of disjoint paths a lookup takes (S/Kademlia), no node is
+	/// asked by more than one of them
+	pub lookup_paths: usize,
+	/// Compress the values of `put()` and `store()` if that makes them
+	/// smaller, see `compressed`
+	pub compress_values: bool,
+	/// Look up a random id in buckets we did not hear of for this long
+	pub refresh_interval: Duration,
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			ttl:              Duration::from_secs(15*60),
+			storage_dir:      None,
+			mainline:         false,
+			verify_ids:       false,
+			id_difficulty:    0,
+			lookup_paths:     1,
+			compress_values:  false,
+			refresh_interval: Duration::from_secs(15*60),
+		}
+	}
+}
+
+/// A value kept alive by `store()`, see `Kademlia::get_stored_values()`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredValue {
+	pub key:        NodeId,
+	pub value:      Vec<u8>,
+	/// wall-clock time in seconds since UNIX_EPOCH
+	pub expires_at: u64,
+}
 
 #[derive(Clone)]
 pub struct Kademlia {
@@ -33,34 +98,70 @@ pub struct Kademlia {
 	stored_values: Arc<RwLock<HashMap<NodeId, (u64, Vec<u8>)>>>,
 	server: Server,
 	kbuckets: KBuckets,
-	external_values: storage::ExternalStorage,
+	external_values: storage::ExternalStorage<Value>,
 	listeners: storage::ExternalStorage,
-	ttl: Duration,
+	identity: Arc<RwLock<Identity>>,
+	write_tokens: Arc<WriteTokens>,
+	/// the write tokens other nodes handed to us
+	peer_tokens: Arc<PeerTokens>,
+	config: Config,
 }
 
 impl Kademlia {
+	pub fn builder() -> KademliaBuilder {
+		KademliaBuilder::new()
+	}
+
 	#[allow(dead_code)]
-	pub fn new_supernode<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>) -> Kademlia {
-		let own_id = own_id.or_else(|| Some(Node::generate_id()));
-		Self::create(handle, addr, own_id)
+	pub fn new_supernode<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>) -> Result<Kademlia> {
+		let own_id = own_id.unwrap_or_else(|| Node::generate_id());
+		KademliaBuilder::new()
+			.listen(addr)
+			.node_id(own_id)
+			.create(handle)
+	}
+
+	pub fn create(handle: Handle, addr: SocketAddr, own_id: Option<NodeId>, config: Config) -> Result<Kademlia> {
+		let udp = try!(UdpSocket::bind(addr));
+		Self::with_transport(handle, Arc::new(udp), own_id, config)
 	}
 
-	pub fn create<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>) -> Kademlia {
-		let udp = UdpSocket::bind(addr).unwrap();
-		let server = Server::new(handle, udp);
+	pub fn with_transport(handle: Handle, transport: Arc<Transport>, own_id: Option<NodeId>, config: Config)
+		-> Result<Kademlia>
+	{
+		let identity = Identity::generate(config.id_difficulty);
+		let own_id = own_id.unwrap_or_else(|| identity.node_id());
+		let identity = Arc::new(RwLock::new(identity));
 
-		let ttl = Duration::from_secs(15*60);
-		let own_id = own_id.unwrap_or_else(|| Node::generate_id());
+		let codec:Arc<Codec> = if config.mainline {
+			Arc::new(Krpc::new())
+		} else {
+			Arc::new(protocol::Native::new(identity.clone()))
+		};
 		let own_id = Arc::new(Mutex::new(own_id));
+		let server = try!(Server::new(handle, transport, codec, own_id.clone()));
+
+		let ttl = config.ttl;
+
+		let external_values = match config.storage_dir {
+			None => storage::ExternalStorage::new(ttl),
+			Some(ref dir) => {
+				let backend = try!(storage::FileBackend::open(dir));
+				storage::ExternalStorage::with_backend(ttl, Box::new(backend))
+			}
+		};
 
 		let kad = Kademlia {
 			own_id:          own_id.clone(),
 			server:          server.clone(),
 			stored_values:   Arc::new(RwLock::new(HashMap::new())),
 			kbuckets:        KBuckets::new(own_id),
-			external_values: storage::ExternalStorage::new(ttl),
+			external_values: external_values,
 			listeners:       storage::ExternalStorage::new(ttl),
-			ttl:             ttl,
+			identity:        identity,
+			write_tokens:    Arc::new(WriteTokens::new()),
+			peer_tokens:     Arc::new(PeerTokens::new()),
+			config:          config,
 		};
 
 		let this = kad.clone();
@@ -80,14 +181,21 @@ impl Kademlia {
 		let this = kad.clone();
 		let handle = this.server.handle.clone();
 		handle.spawn(Interval::new(Duration::from_secs(60), &handle).unwrap().for_each(move |_| {
-			let node_id = Node::generate_id();
-			this.find_node(node_id);
-			Ok(()) as Result<(), io::Error>
+			let refresh_interval = this.config.refresh_interval;
+			this.refresh_buckets(refresh_interval);
+
+			// verified ids are only looked up for nodes we are about to add
+			let addrs = this.kbuckets.get_nodes().into_iter().map(|n| n.addr).collect();
+			this.server.retain_verified_ids(&addrs);
+			debug!("Routing table: {} nodes, about {} peers in the network",
+				this.kbuckets.get_nodes().len(), this.kbuckets.estimate_peers_in_network());
+			Ok(()) as io::Result<()>
 		}).map_err(|_| ()));
 
 		let mut this = kad.clone();
 		handle.spawn(Interval::new(Duration::from_secs(5*60), &handle).unwrap().for_each(move |_| {
 			// publish stored values again and again
+			let handle = this.server.handle.clone();
 			let stored_values = this.stored_values.clone();
 			let mut store = stored_values.write().unwrap();
 
@@ -96,22 +204,31 @@ impl Kademlia {
 				*lifetime = lifetime.saturating_sub(5 * 60);
 
 				if *lifetime > 0 {
-					this.put(*key, value.clone()).unwrap();
+					handle.spawn(this.put(*key, value.clone()).map_err(|_| ()));
 				}
 			}
 
-			Ok(()) as Result<(), io::Error>
+			Ok(()) as io::Result<()>
 		}).map_err(|_| ()));
 
-		kad
+		Ok(kad)
 	}
 
 	pub fn bootstrap<A,B>(handle: Handle, addr: A, supernodes: Vec<B>, new_id: Option<NodeId>)
-		-> Kademlia
+		-> Result<Kademlia>
 		where A: ToSocketAddrs, B: ToSocketAddrs
 	{
-		let mut kad = Self::create(handle, addr, None);
+		let builder = KademliaBuilder::new()
+			.listen(addr)
+			.supernodes(supernodes);
+
+		match new_id {
+			None     => builder.bootstrap(handle),
+			Some(id) => builder.node_id(id).bootstrap(handle),
+		}
+	}
 
+	pub fn join(&mut self, supernodes: Vec<SocketAddr>, new_id: Option<NodeId>) {
 		for address in supernodes.into_iter() {
 			/*
 			 * Let's use some random NodeId.
@@ -121,40 +238,156 @@ impl Kademlia {
 			let node_id = Node::generate_id();
 			let node = Node::new(address, node_id);
 
-			ignore(node.map(|n| kad.kbuckets.add(n)));
+			ignore(node.map(|n| self.kbuckets.add(n)));
 		}
 
-		let mut new_id = new_id.unwrap_or_else(|| Node::generate_id());
+		let mut new_id = new_id.unwrap_or_else(|| self.get_own_id());
 		loop {
-			kad.set_own_id(new_id);
+			self.set_own_id(new_id);
 
-			let node_list = kad.find_node(new_id);
+			let node_list = self.find_node(new_id);
 
 			if !node_list.iter().any(|n|
 					n.node_id == new_id &&
-					n.addr != kad.server.local_addr().unwrap() //TODO: unwrap!?
+					n.addr != self.server.local_addr
 				) {
 
 				for n in node_list.into_iter() {
-					ignore(kad.kbuckets.add(n));
+					ignore(self.kbuckets.add(n));
 				}
 
 				break;
 			}
 
-			new_id = Node::generate_id();
+			let identity = Identity::generate(self.config.id_difficulty);
+			new_id = identity.node_id();
+			*self.identity.write().unwrap() = identity;
 		}
 
-		kad
+		// the lookup of our own id filled our neighbourhood, now fill the
+		// buckets farther away
+		self.refresh_buckets(Duration::from_secs(0));
+	}
+
+	/// Look up a random id in every bucket that was not touched for
+	/// `max_age`, see `KBuckets::stale_ids()`. Runs every minute with
+	/// `Config::refresh_interval`.
+	///
+	/// The lookups run in the background, a bucket counts as refreshed
+	/// once its lookup finished.
+	pub fn refresh_buckets(&self, max_age: Duration) {
+		let handle = self.server.handle.clone();
+
+		for id in self.kbuckets.stale_ids(max_age).into_iter() {
+			debug!("Refreshing bucket of {}", enc_id(&id));
+
+			let kbuckets = self.kbuckets.clone();
+			handle.spawn(self.find_node(id).then(move |_| {
+				kbuckets.touch(&id);
+				Ok::<(), ()>(())
+			}));
+		}
 	}
 
 	pub fn get_nodes(&self) -> Vec<Node> {
 		self.kbuckets.get_nodes()
 	}
 
+	/// The buckets and their nodes with their liveness, for debugging
+	pub fn dump_routing_table(&self) -> String {
+		format!("{:?}", self.kbuckets)
+	}
+
 	pub fn get(&self, key: NodeId) -> Vec<Vec<u8>> {
+		self.get_values(key).into_iter()
+			.filter_map(compressed::open)
+			.collect()
+	}
+
+	/// Get the most recent version of the mutable value owned by
+	/// `public_key` and its sequence number
+	pub fn get_mutable(&self, public_key: &PublicKey, salt: &[u8]) -> Option<(u64, Vec<u8>)> {
+		let key = mutable::mutable_key(public_key, salt);
+
+		self.get_values(key).into_iter()
+			.filter_map(|v| v.seq().map(|seq| (seq, v.data)))
+			.max_by_key(|&(seq, _)| seq)
+	}
+
+	/// Like `get()`, but only returns values sealed by `put_sealed()` or
+	/// `store_sealed()` with the same `app_key`
+	pub fn get_sealed(&self, app_key: &AppKey, key: NodeId) -> Vec<Vec<u8>> {
+		self.get(key).into_iter()
+			.filter_map(|v| app_key.open(&key, &v))
+			.collect()
+	}
+
+	/// Returns a Stream of values stored under `key` from now on.
+	///
+	/// The k closest nodes are asked to push new values to us. This request
+	/// is renewed before it expires until the `Watch` is dropped.
+	pub fn watch(&self, key: NodeId) -> Watch {
+		let cookie = Self::generate_cookie();
+		let watch = Watch::new(key, cookie, self.server.clone());
+
+		let handle = self.server.handle.clone();
+		handle.spawn(self.listen(key, cookie).map_err(|_| ()));
+
+		// renew well before the listeners' ttl runs out
+		let this = self.clone();
+		let renew = Interval::new(self.config.ttl * 2 / 3, &handle).unwrap();
+		handle.spawn(renew.map_err(|_| ()).for_each(move |_| {
+			if !this.server.is_subscribed(&cookie) {
+				return Err(());
+			}
+
+			let handle = this.server.handle.clone();
+			handle.spawn(this.listen(key, cookie).map_err(|_| ()));
+			Ok(())
+		}));
+
+		watch
+	}
+
+	#[async]
+	fn listen(&self, key: NodeId, cookie: Cookie) -> Result<()> {
+		let msg = Message::Listen(Listen {
+			sender_id: self.get_own_id(),
+			cookie:    cookie,
+			key:       key,
+		});
+
+		let nodes:Vec<Node> = try!(await!(self.find_node(key))).into_iter()
+			.filter(|n| self.supports(n, CAP_LISTEN))
+			.collect();
+		let nodes_len = nodes.len();
+
+		for n in nodes {
+			self.server.hit_and_run(n.addr, &msg);
+		}
+
+		if nodes_len > 0 {
+			debug!("Listening for {} on {:?} nodes.", enc_id(&key), nodes_len);
+			Ok(())
+		} else {
+			warn!("Could not find any nodes to listen for {}!", enc_id(&key));
+			Err(Error::NoNodesFound)
+		}
+	}
+
+	/// Get the immutable value stored under its hash `key`
+	pub fn get_immutable(&self, key: NodeId) -> Option<Vec<u8>> {
+		self.get_values(key).into_iter()
+			.find(|v| v.immutable)
+			.map(|v| v.data)
+	}
+
+	fn get_values(&self, key: NodeId) -> Vec<Value> {
 		debug!("Finding {}...", enc_id(&key));
-        let values:Vec<Vec<u8>> = self.find_value(key).iter().collect();
+		let values:Vec<Value> = self.find_value(key).iter()
+			.filter(|v| v.verify(&key))
+			.collect();
+
 		if values.len() > 0 {
 			info!("Found {:?} values for {}", values.len(), enc_id(&key));
 		} else {
@@ -172,47 +405,416 @@ impl Kademlia {
 		*own_id = new_id;
 	}
 
-    /// Just store a value once
-    #[async]
-	pub fn put(&mut self, key: NodeId, value: Vec<u8>) -> Result<(),Vec<u8>> {
+	/// Just store a value once
+	#[async]
+	pub fn put(&mut self, key: NodeId, value: Vec<u8>) -> Result<()> {
+		let len = value.len();
+		let value = compressed::new_value(value, self.config.compress_values);
+
+		// the limit applies to what goes over the wire
+		if value.data.len() > MAX_VALUE_LEN {
+			return Err(Error::ValueTooLarge(len));
+		}
+
+		await!(self.publish(key, value, None))
+	}
+
+	/// Stop storing the value we put under `key` and ask the nodes
+	/// storing it to drop it right away.
+	///
+	/// Nodes only drop the value if the `Remove` comes from the same
+	/// address and NodeId that stored it and carries the write token they
+	/// issued to that address, so the sender cannot be spoofed. They
+	/// acknowledge it with `Stored`, this fails unless one of them did.
+	#[async]
+	pub fn remove(&mut self, key: NodeId) -> Result<()> {
+		{
+			let mut stored_values = self.stored_values.write().unwrap();
+			stored_values.remove(&key);
+		}
+
+		let nodes:Vec<Node> = try!(await!(self.find_node(key))).into_iter()
+			.filter(|n| self.supports(n, CAP_REMOVE))
+			.collect();
+
+		let requests:Vec<_> = self.with_tokens(key, nodes).into_iter()
+			.map(|(n, token)| {
+				let msg = Message::Remove(Remove {
+					sender_id: self.get_own_id(),
+					cookie:    Self::generate_cookie(),
+					key:       key,
+					token:     token,
+				});
+
+				let rx = self.server.send_request_ms(&n.addr, &msg, TIMEOUT_MS);
+				(n, rx)
+			})
+			.collect();
+		let nodes_len = requests.len();
+
+		let mut removed = 0;
+		let mut rejected = vec![];
+
+		let replies = Self::replies(requests, |resp| match *resp {
+			Message::Stored(_) | Message::Error(_) => true,
+			_ => false,
+		});
+
+		for (node, resp) in replies {
+			match resp {
+				Message::Stored(_) => removed += 1,
+				Message::Error(error) => {
+					info!("{:?} did not remove {}: {}", node, enc_id(&key), error.code);
+					rejected.push(error.code);
+				},
+				_ => {
+					debug!("{:?} did not acknowledge removing {}", node, enc_id(&key));
+					self.kbuckets.record_failure(&node);
+				},
+			}
+		}
+
+		if removed > 0 {
+			info!("Removed {} from {} of {} nodes.", enc_id(&key), removed, nodes_len);
+			Ok(())
+		} else if let Some(&code) = rejected.first() {
+			warn!("No node removed {}!", enc_id(&key));
+			Err(Error::Rejected(code))
+		} else {
+			warn!("Could not find any nodes to remove {} from!", enc_id(&key));
+			Err(Error::NoNodesFound)
+		}
+	}
+
+	/// The values we currently keep alive, e.g. to save them before a restart
+	pub fn get_stored_values(&self) -> Vec<StoredValue> {
+		let now = storage::now();
+		let stored_values = self.stored_values.read().unwrap();
+
+		stored_values.iter()
+			.filter(|&(_, &(lifetime, _))| lifetime > 0)
+			.map(|(key, &(lifetime, ref value))| StoredValue {
+				key:        *key,
+				value:      value.clone(),
+				expires_at: now + lifetime,
+			})
+			.collect()
+	}
+
+	/// Keep storing values returned by `get_stored_values()` for the rest
+	/// of their lifetime and publish them right away
+	pub fn restore_stored_values(&mut self, values: Vec<StoredValue>) {
+		let now = storage::now();
+		let values:Vec<StoredValue> = values.into_iter()
+			.filter(|v| v.expires_at > now)
+			.collect();
+
+		{
+			let mut stored_values = self.stored_values.write().unwrap();
+			for v in values.iter() {
+				stored_values.insert(v.key, (v.expires_at - now, v.value.clone()));
+			}
+		}
+
+		info!("Republishing {} stored values", values.len());
+
+		let handle = self.server.handle.clone();
+		for v in values.into_iter() {
+			handle.spawn(self.put(v.key, v.value).map_err(|_| ()));
+		}
+	}
+
+	/// Like `put()`, but `value` is encrypted and authenticated with `app_key`
+	#[async]
+	pub fn put_sealed(&mut self, app_key: AppKey, key: NodeId, value: Vec<u8>) -> Result<()> {
+		let sealed = app_key.seal(&key, &value);
+		await!(self.put(key, sealed))
+	}
+
+	/// Like `store()`, but `value` is encrypted and authenticated with `app_key`
+	#[async]
+	pub fn store_sealed(&mut self, app_key: AppKey, key: NodeId, value: Vec<u8>, lifetime: u64) -> Result<()> {
+		let sealed = app_key.seal(&key, &value);
+		await!(self.store(key, sealed, lifetime))
+	}
+
+	/// Like `put()`, but for values up to `MAX_CHUNKED_VALUE_LEN` bytes.
+	///
+	/// The value is split into immutable chunks and a manifest listing
+	/// them is stored under `key`. Use `get_large()` to fetch it.
+	///
+	/// The manifest is not signed: like with `put()`, any peer can put
+	/// its own manifest under `key` and `get_large()` returns that value
+	/// as well. The chunks only prove that a value matches its manifest,
+	/// not who put it.
+	#[async]
+	pub fn put_large(&mut self, key: NodeId, value: Vec<u8>) -> Result<()> {
+		let (manifest, chunks) = match chunked::split(&value) {
+			None => return Err(Error::ValueTooLarge(value.len())),
+			Some(res) => res,
+		};
+
+		let publish:Vec<_> = chunks.into_iter()
+			.map(|(chunk_key, chunk)| self.publish(chunk_key, chunk, None))
+			.collect();
+		try!(await!(join_all(publish)));
+
+		info!("Published {} chunks of {}", manifest.chunks.len(), enc_id(&key));
+		await!(self.publish(key, Value::new(manifest.encode()), None))
+	}
+
+	/// Get all values stored by `put_large()` under `key`.
+	///
+	/// The chunks are fetched in parallel, values with missing or
+	/// corrupt chunks are skipped.
+	#[async]
+	pub fn get_large(&self, key: NodeId) -> Result<Vec<Vec<u8>>> {
+		let manifests:Vec<Manifest> = self.get(key).iter()
+			.filter_map(|data| Manifest::decode(data))
+			.collect();
+
+		let mut values = vec![];
+		for manifest in manifests.into_iter() {
+			let fetch:Vec<_> = manifest.chunks.iter()
+				.map(|chunk_key| self.fetch_chunk(*chunk_key))
+				.collect();
+			let chunks = try!(await!(join_all(fetch)));
+
+			let chunks:HashMap<NodeId, Vec<u8>> = chunks.into_iter()
+				.filter_map(|c| c)
+				.collect();
+
+			match manifest.assemble(chunks) {
+				Some(value) => values.push(value),
+				None => warn!("Missing or corrupt chunks for {}", enc_id(&key)),
+			}
+		}
+
+		Ok(values)
+	}
+
+	/// Returns the first valid chunk stored under `key`
+	fn fetch_chunk(&self, key: NodeId) -> impl Future<Item=Option<(NodeId, Vec<u8>)>, Error=Error> {
+		self.find_value(key)
+			.filter(move |v| v.immutable && v.verify(&key))
+			.into_future()
+			.map(move |(v, _)| v.map(|v| (key, v.data)))
+			.map_err(|_| Error::NoNodesFound)
+	}
+
+	/// Announce that we are a peer for `info_hash`, listening on `port`.
+	/// Port 0 announces the port we send from (`implied_port`), which
+	/// also works behind a NAT if the peer shares the DHT's socket.
+	///
+	/// Together with `get_peers()` this works like BitTorrent's
+	/// announce_peer and get_peers when `Config::mainline` is set.
+	/// Mainline nodes take our IP address from the datagram.
+	#[async]
+	pub fn announce_peer(&mut self, info_hash: NodeId, port: u16) -> Result<()> {
+		// mainline nodes hand out the write tokens in get_peers responses
+		ignore(await!(self.find_value(info_hash).collect()));
+
+		let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+		await!(self.publish(info_hash, Value::new(node::compact_addr(&addr)), None))
+	}
+
+	/// The peers announced for `info_hash`
+	pub fn get_peers(&self, info_hash: NodeId) -> Vec<SocketAddr> {
+		self.get(info_hash).iter()
+			.filter_map(|v| node::parse_compact_addr(v))
+			.collect()
+	}
+
+	/// Store `value` once under its hash, which is returned.
+	///
+	/// Storing nodes and readers reject values that do not match the hash.
+	#[async]
+	pub fn put_immutable(&mut self, value: Vec<u8>) -> Result<NodeId> {
 		if value.len() > MAX_VALUE_LEN {
-			return Err(value);
+			return Err(Error::ValueTooLarge(value.len()));
 		}
 
-		Ok(self.publish(key, value))
+		let (key, value) = immutable::new_value(value);
+
+		try!(await!(self.publish(key, value, None)));
+		Ok(key)
 	}
 
-    /// Store a value permanently for `lifetime`
-    #[async]
-	pub fn store(&mut self, key: NodeId, value: Vec<u8>, lifetime: u64) -> Result<(),Vec<u8>> {
-		await!(self.put(key, value));
+	/// Sign `value` with `keypair` and store it once under
+	/// `mutable_key(keypair.public_key(), salt)`.
+	///
+	/// Storing nodes only keep the value with the highest `seq`, so this
+	/// fails with `Error::Conflict` if all that answered already have a
+	/// newer version.
+	#[async]
+	pub fn put_mutable(&mut self, keypair: Keypair, salt: Vec<u8>, seq: u64, value: Vec<u8>)
+		-> Result<NodeId>
+	{
+		if value.len() > MAX_VALUE_LEN {
+			return Err(Error::ValueTooLarge(value.len()));
+		}
+
+		if salt.len() > MAX_SALT_LEN {
+			return Err(Error::SaltTooLarge(salt.len()));
+		}
+
+		let key = mutable::mutable_key(&keypair.public_key(), &salt);
+		let value = keypair.sign(&salt, seq, value);
 
-	    spawn(iter.take(lifetime/timeout).map(|| {
-    		self.put(key, value);
-        }));
+		try!(await!(self.publish(key, value, None)));
+		Ok(key)
 	}
 
-    #[async]
-	fn publish(&self, key: NodeId, value: Vec<u8>) -> Result<(),()> {
-		let msg = Message::Store(Store {
-			sender_id: self.get_own_id(),
-			cookie:    Self::generate_cookie(),
-			key:       key,
-			value:     Value::new(value),
-		});
+	/// Like `put_mutable()`, but the value is only stored as version
+	/// `expected_seq + 1` if the stored version is still `expected_seq`
+	/// (0 if no version may be stored yet).
+	///
+	/// Fails with `Error::Conflict` if someone else updated the value first
+	/// and no node took this version.
+	#[async]
+	pub fn put_if(&mut self, keypair: Keypair, salt: Vec<u8>, expected_seq: u64, value: Vec<u8>)
+		-> Result<NodeId>
+	{
+		if value.len() > MAX_VALUE_LEN {
+			return Err(Error::ValueTooLarge(value.len()));
+		}
 
-		let nodes = await!(self.find_node(key));
-		let nodes_len = nodes.len();
+		if salt.len() > MAX_SALT_LEN {
+			return Err(Error::SaltTooLarge(salt.len()));
+		}
 
-		for n in nodes {
-			self.server.hit_and_run(n.addr, &msg);
+		let key = mutable::mutable_key(&keypair.public_key(), &salt);
+		let value = keypair.sign(&salt, expected_seq + 1, value);
+
+		try!(await!(self.publish(key, value, Some(expected_seq))));
+		Ok(key)
+	}
+
+	/// Store a value permanently for `lifetime` seconds
+	#[async]
+	pub fn store(&mut self, key: NodeId, value: Vec<u8>, lifetime: u64) -> Result<()> {
+		try!(await!(self.put(key, value.clone())));
+
+		let mut stored_values = self.stored_values.write().unwrap();
+		stored_values.insert(key, (lifetime, value));
+
+		Ok(())
+	}
+
+	/// Store `value` on the nodes closest to `key`.
+	///
+	/// Succeeds if any node stored it. Otherwise fails with the newest
+	/// version of a `Conflict` reply, or else the first `Error` code.
+	#[async]
+	fn publish(&self, key: NodeId, value: Value, cas: Option<u64>) -> Result<()> {
+		let nodes = try!(await!(self.find_node(key)));
+
+		// every node wants to see its own write token
+		let requests:Vec<_> = self.with_tokens(key, nodes).into_iter()
+			.map(|(n, token)| {
+				let msg = Message::Store(Store {
+					sender_id: self.get_own_id(),
+					cookie:    Self::generate_cookie(),
+					key:       key,
+					value:     value.clone(),
+					cas:       cas,
+					token:     token,
+				});
+
+				let rx = self.server.send_request_ms(&n.addr, &msg, TIMEOUT_MS);
+				(n, rx)
+			})
+			.collect();
+		let nodes_len = requests.len();
+
+		// every node answers with Stored, Conflict or Error, a lost
+		// packet is as good as a rejection
+		let mut stored = 0;
+		let mut conflict_seq = None;
+		let mut rejected = vec![];
+
+		let replies = Self::replies(requests, |resp| match *resp {
+			Message::Stored(_) | Message::Conflict(_) | Message::Error(_) => true,
+			_ => false,
+		});
+
+		for (node, resp) in replies {
+			match resp {
+				Message::Stored(_) => stored += 1,
+				Message::Conflict(conflict) => {
+					info!("{:?} has version {} of {}", node, conflict.seq, enc_id(&key));
+					conflict_seq = conflict_seq.max(Some(conflict.seq));
+				},
+				Message::Error(error) => {
+					info!("{:?} rejected {}: {}", node, enc_id(&key), error.code);
+					rejected.push(error.code);
+				},
+				_ => {
+					debug!("{:?} did not acknowledge {}", node, enc_id(&key));
+					self.kbuckets.record_failure(&node);
+				},
+			}
 		}
 
-		if nodes_len > 0 {
-			info!("Published {} on {:?} nodes.", enc_id(&key), nodes_len);
+		if stored > 0 {
+			info!("Published {} on {} of {} nodes.", enc_id(&key), stored, nodes_len);
+			Ok(())
+		} else if let Some(seq) = conflict_seq {
+			info!("No node took {}, they have version {}", enc_id(&key), seq);
+			Err(Error::Conflict(seq))
+		} else if let Some(&code) = rejected.first() {
+			warn!("No node accepted {}!", enc_id(&key));
+			Err(Error::Rejected(code))
 		} else {
 			warn!("Could not find any nodes to publish {}!", enc_id(&key));
+			Err(Error::NoNodesFound)
+		}
+	}
+
+	/// Whether `node` may be added to the routing table: with
+	/// `Config::verify_ids` it must have signed its last response to us
+	/// with the key its NodeId is derived from
+	fn is_verified(&self, node: &Node) -> bool {
+		if !self.config.verify_ids {
+			return true;
 		}
+
+		self.server.verified_id(&node.addr) == Some(node.node_id)
+			&& identity::solves_puzzle(&node.node_id, self.config.id_difficulty)
+	}
+
+	/// Nodes we did not hear from yet are assumed to support everything
+	fn supports(&self, node: &Node, capability: u32) -> bool {
+		self.server.capabilities(&node.addr)
+			.map_or(true, |c| c & capability == capability)
+	}
+
+	/// Send `req` along `Config::lookup_paths` disjoint lookups towards `key`.
+	///
+	/// Every response comes with the index of its path, nodes learned from
+	/// it must only be added to that path's iterator.
+	fn send_disjoint(&self, key: NodeId, req: Message)
+		-> (Vec<ClosestNodesIter>, mpsc::Receiver<(usize, Node, Message)>)
+	{
+		self.kbuckets.touch(&key);
+
+		let closest = self.kbuckets.get_closest_nodes(&key, K_PARAM);
+		let paths = ClosestNodesIter::disjoint(key, K_PARAM, closest, self.config.lookup_paths);
+
+		let (tx, rx) = mpsc::channel();
+		for (i, iter) in paths.iter().enumerate() {
+			let path_rx = self.server.send_many_request(iter.clone(), req.clone(), TIMEOUT_MS, ALPHA_PARAM);
+			let tx = tx.clone();
+			spawn(move || {
+				for (sender, resp) in path_rx {
+					if tx.send((i, sender, resp)).is_err() {
+						break;
+					}
+				}
+			});
+		}
+
+		(paths, rx)
 	}
 
 	fn generate_cookie() -> Cookie {
@@ -221,47 +823,24 @@ impl Kademlia {
 		cookie
 	}
 
-	fn ping_or_replace_with(&mut self, replacement: Node) {
-		let node_list = {
-			let bucket = self.kbuckets.get_bucket(&replacement.node_id);
-
-			let mut node_list:Vec<Node> = bucket.map(|b| b.clone()).unwrap_or(vec![]);
-			node_list.sort_by(|a,b| {
-				let x = *a.last_seen.lock().unwrap();
-				let y = *b.last_seen.lock().unwrap();
-				x.cmp(&y)
-			});
-			node_list
-		};
-
+	/// Ping `node` in the background and let the replacement cache take
+	/// its slot if it does not answer, see `KBuckets::cache_replacement()`
+	fn probe(&self, node: Node) {
 		let req = Message::Ping(Ping {
 			sender_id: self.get_own_id(),
 			cookie:    Self::generate_cookie(),
 		});
 
-		let rx = self.server.send_many_request(node_list.into_iter(), req, TIMEOUT_MS, ALPHA_PARAM);
-		
-		for (node, resp) in rx {
-			match resp {
-				Message::Pong(_) => (),
-				_ => {
-					let bucket = self.kbuckets.get_mut_bucket(&replacement.node_id);
-					if bucket.is_none() {
-						return
-					}
+		let mut this = self.clone();
+		spawn(move || {
+			let rx = this.server.send_request_ms(&node.addr, &req, TIMEOUT_MS);
+			let alive = match rx.recv() {
+				Ok(Message::Pong(_)) => true,
+				_ => false,
+			};
 
-					let mut bucket = bucket.unwrap();
-					match bucket.iter().position(|n| *n == node) {
-						None => continue, // hey, where is that node gone?!
-						Some(pos) => {
-							bucket.remove(pos);
-							bucket.push(replacement);
-							return;
-						}
-					}
-				}
-			}
-		}
+			this.kbuckets.probed(&node, alive);
+		});
 	}
 
 	fn update_buckets(&mut self, own_id: &NodeId, src: SocketAddr, msg: &Message)
@@ -284,8 +863,16 @@ impl Kademlia {
 				let mut sender = try!(self.kbuckets.construct_node(src, sender_id));
 				sender.update_last_seen();
 
-				ignore(self.kbuckets.add(sender)
-					.map_err(|sender| self.ping_or_replace_with(sender)));
+				if !self.is_verified(&sender) {
+					debug!("Not adding {:?}: NodeId not verified", sender);
+					return Ok(());
+				}
+
+				if let Err(sender) = self.kbuckets.add(sender) {
+					if let Some(stale) = self.kbuckets.cache_replacement(sender) {
+						self.probe(stale);
+					}
+				}
 			}
 		}
 
@@ -294,6 +881,137 @@ impl Kademlia {
 		Ok(())
 	}
 
+	fn send_error(&self, dst: SocketAddr, cookie: Cookie, code: ErrorCode) {
+		let error = message::Error {
+			sender_id: self.get_own_id(),
+			cookie:    cookie,
+			code:      code,
+		};
+		self.server.send_response(dst, &Message::Error(error));
+	}
+
+	fn remember_token(&self, src: SocketAddr, token: Token) {
+		// e.g. mainline nodes only hand out tokens in get_peers responses
+		if token.is_empty() {
+			return;
+		}
+
+		self.peer_tokens.insert(src, token);
+	}
+
+	/// Pair `nodes` with the write tokens they handed out to us. Nodes we
+	/// hold no fresh token of are asked for one with a `FindValue`, which
+	/// mainline nodes answer with a token as well, and skipped if they
+	/// don't hand one out.
+	fn with_tokens(&self, key: NodeId, nodes: Vec<Node>) -> Vec<(Node, Token)> {
+		let mut with_tokens = vec![];
+		let mut requests = vec![];
+
+		for n in nodes {
+			match self.peer_tokens.get(&n.addr) {
+				Some(token) => with_tokens.push((n, token)),
+				None => {
+					let req = Message::FindValue(FindValue {
+						sender_id: self.get_own_id(),
+						cookie:    Self::generate_cookie(),
+						key:       key,
+					});
+
+					let rx = self.server.send_request_ms(&n.addr, &req, TIMEOUT_MS);
+					requests.push((n, rx));
+				},
+			}
+		}
+
+		let replies = Self::replies(requests, |resp| match *resp {
+			Message::FoundNode(_) | Message::FoundValue(_) => true,
+			_ => false,
+		});
+
+		let mut skipped = 0;
+		for (n, resp) in replies {
+			let token = match resp {
+				Message::FoundNode(found_node) => found_node.token,
+				Message::FoundValue(found_value) => found_value.token,
+				_ => vec![],
+			};
+
+			if token.is_empty() {
+				skipped += 1;
+			} else {
+				self.remember_token(n.addr, token.clone());
+				with_tokens.push((n, token));
+			}
+		}
+
+		if skipped > 0 {
+			info!("Skipped {} nodes without a write token for {}", skipped, enc_id(&key));
+		}
+
+		with_tokens
+	}
+
+	/// The first response of every node to our request that `is_reply`,
+	/// or `Timeout`. The nodes are waited for at the same time.
+	fn replies(requests: Vec<(Node, mpsc::Receiver<Message>)>, is_reply: fn(&Message) -> bool)
+		-> Vec<(Node, Message)>
+	{
+		let (tx, rx) = mpsc::channel();
+
+		for (node, node_rx) in requests {
+			let tx = tx.clone();
+			spawn(move || {
+				let resp = node_rx.iter()
+					.find(|resp| *resp == Message::Timeout || is_reply(resp))
+					.unwrap_or(Message::Timeout);
+				ignore(tx.send((node, resp)));
+			});
+		}
+		drop(tx);
+
+		rx.iter().collect()
+	}
+
+	/// answer with as few datagrams as the nodes fit into
+	fn send_found_nodes(&self, dst: SocketAddr, cookie: Cookie, nodes: Vec<Node>) {
+		let mut found_node = FoundNode {
+			sender_id: self.get_own_id(),
+			cookie:    cookie,
+			nodes:     vec![],
+			token:     self.write_tokens.issue(&dst),
+		};
+		let overhead = protocol::encode(&Message::FoundNode(found_node.clone())).len();
+
+		for nodes in protocol::pack(nodes, overhead).into_iter() {
+			found_node.nodes = nodes;
+			self.server.send_response(dst, &Message::FoundNode(found_node.clone()));
+		}
+	}
+
+	/// answer with as few datagrams as the values fit into
+	fn send_found_values(&self, dst: SocketAddr, cookie: Cookie, values: Vec<Value>) {
+		let mut found_value = FoundValue {
+			sender_id: self.get_own_id(),
+			cookie:    cookie,
+			values:    vec![],
+			token:     self.write_tokens.issue(&dst),
+		};
+		let overhead = protocol::encode(&Message::FoundValue(found_value.clone())).len();
+
+		for values in protocol::pack(values, overhead).into_iter() {
+			for values in values.chunks(MAX_FOUND_VALUES) {
+				found_value.values = values.to_vec();
+				self.server.send_response(dst, &Message::FoundValue(found_value.clone()));
+			}
+		}
+	}
+
+	#[cfg(feature="fuzzing")]
+	#[doc(hidden)]
+	pub fn fuzz_handle_message(&mut self, src: SocketAddr, msg: Message) -> io::Result<()> {
+		self.handle_message(src, msg)
+	}
+
 	fn handle_message(&mut self, src: SocketAddr, msg: Message)
 		-> io::Result<()>
 	{
@@ -311,62 +1029,67 @@ impl Kademlia {
 			}
 			Message::FindNode(find_node) => {
 				let node_list = self.kbuckets.get_closest_nodes(&find_node.key, K_PARAM);
-				let count = node_list.len();
-
-				for node in node_list.into_iter() {
-					let found_node = FoundNode {
-						sender_id:  own_id,
-						cookie:     find_node.cookie,
-						node_count: count,
-						node:       node,
-					};
-					self.server.send_response(src, &Message::FoundNode(found_node));
-				}
+				self.send_found_nodes(src, find_node.cookie, node_list);
 			},
 			Message::FindValue(find_value) => {
 				let value_list = self.external_values.get(&find_value.key);
 
 				if value_list.len() > 0 {
-					let count = value_list.len();
-
-					for (_, value) in value_list.into_iter() {
-						let found_value = FoundValue {
-							sender_id:   own_id,
-							cookie:      find_value.cookie,
-							value_count: count,
-							value:       Value::new(value),
-						};
-						self.server.send_response(src, &Message::FoundValue(found_value));
-					}
+					let values = value_list.into_iter().map(|(_, value)| value).collect();
+					self.send_found_values(src, find_value.cookie, values);
 				} else {
 					let node_list = self.kbuckets.get_closest_nodes(&find_value.key, K_PARAM);
-					let count = node_list.len();
-
-					for node in node_list.into_iter() {
-						let found_node = FoundNode {
-							sender_id:  own_id,
-							cookie:     find_value.cookie,
-							node_count: count,
-							node:       node,
-						};
-						self.server.send_response(src, &Message::FoundNode(found_node));
-					}
+					self.send_found_nodes(src, find_value.cookie, node_list);
 				}
 			},
 			Message::Store(store) => {
-				if store.value.len() <= MAX_VALUE_LEN {
+				if !self.write_tokens.verify(&src, &store.token) {
+					debug!("Invalid write token for {} from {}", enc_id(&store.key), src);
+					self.send_error(src, store.cookie, ErrorCode::InvalidToken);
+					return Ok(());
+				}
+
+				if !store.value.verify(&store.key) {
+					warn!("Invalid value for {} from {}", enc_id(&store.key), src);
+					self.send_error(src, store.cookie, ErrorCode::BadSignature);
+					return Ok(());
+				}
+
+				if store.value.len() > MAX_VALUE_LEN {
+					self.send_error(src, store.cookie, ErrorCode::ValueTooLarge);
+				} else {
 					let sender = (src, store.sender_id);
-					self.external_values.put(store.key, sender, (*store.value).clone());
+					let res = self.external_values.put_value(store.key, sender, store.value.clone(), store.cas);
+
+					if let Err(seq) = res {
+						debug!("Ignoring outdated value for {}", enc_id(&store.key));
+
+						let conflict = Conflict {
+							sender_id: own_id,
+							cookie:    store.cookie,
+							key:       store.key,
+							seq:       seq,
+						};
+						self.server.send_response(src, &Message::Conflict(conflict));
+						return Ok(());
+					}
+
+					let stored = Stored {
+						sender_id: own_id,
+						cookie:    store.cookie,
+						key:       store.key,
+					};
+					self.server.send_response(src, &Message::Stored(stored));
 
 					for ((dst, _), cookie_vec) in self.listeners.get(&store.key) {
 					    let mut cookie = [0; COOKIE_BYTELEN];
 					    cookie.copy_from_slice(&cookie_vec);
 
                         let found_value = FoundValue {
-							sender_id:   own_id,
-							cookie:      cookie,
-							value_count: 1,
-							value:       Value::new((*store.value).clone()),
+							sender_id: own_id,
+							cookie:    cookie,
+							values:    vec![store.value.clone()],
+							token:     self.write_tokens.issue(&dst),
                         };
                         self.server.send_response(dst, &Message::FoundValue(found_value));
 					}
@@ -376,28 +1099,48 @@ impl Kademlia {
 				let sender = (src, listen.sender_id);
                 self.listeners.put(listen.key, sender, listen.cookie.to_vec())
 			},
+			Message::Remove(remove) => {
+				if !self.write_tokens.verify(&src, &remove.token) {
+					debug!("Invalid write token for removing {} from {}", enc_id(&remove.key), src);
+					self.send_error(src, remove.cookie, ErrorCode::InvalidToken);
+					return Ok(());
+				}
+
+				let sender = (src, remove.sender_id);
+				if self.external_values.remove(&remove.key, &sender) {
+					debug!("Removed value for {} from {}", enc_id(&remove.key), src);
+				}
+
+				// nothing of the sender is stored under the key anymore
+				let stored = Stored {
+					sender_id: self.get_own_id(),
+					cookie:    remove.cookie,
+					key:       remove.key,
+				};
+				self.server.send_response(src, &Message::Stored(stored));
+			},
+			Message::FoundNode(found_node) => self.remember_token(src, found_node.token),
+			Message::FoundValue(found_value) => self.remember_token(src, found_value.token),
 			Message::Timeout
 			| Message::Pong(_)
-			| Message::FoundNode(_)
-			| Message::FoundValue(_) => (),
+			| Message::Conflict(_)
+			| Message::Stored(_)
+			| Message::Error(_) => (),
 		};
 
 		Ok(())
 	}
 
-	fn find_value(&self, key: NodeId) -> impl Stream<Vec<u8>> {
+	fn find_value(&self, key: NodeId) -> impl Stream<Value> {
         let own_id = self.get_own_id();
-		let closest = self.kbuckets.get_nodes();
-	    debug!("FindValue: {:?} initial nodes", closest.len());
-
-	    let iter = ClosestNodesIter::new(key, K_PARAM, closest);
 
 	    let req = Message::FindValue(FindValue {
 		    cookie:    Self::generate_cookie(),
 		    sender_id: self.get_own_id(),
 		    key:       key,
 	    });
-	    let rx = self.server.send_many_request(iter.clone(), req, TIMEOUT_MS, ALPHA_PARAM); //chain channels??
+	    let (paths, rx) = self.send_disjoint(key, req);
+	    debug!("FindValue: {} paths", paths.len());
 
         let (result_tx, result_rx) = mpsc::channel(2048);
 
@@ -407,20 +1150,23 @@ impl Kademlia {
 
         #[async]
         for resp in rx.select2(timeout) {
-            if let Either::A((_, resp), timeout) = res? {
+            if let Either::A((path, sender, resp), timeout) = res? {
 			    match resp {
+				    Message::Timeout => self.kbuckets.record_failure(&sender),
 				    Message::FoundNode(found_node) => {
-					    let node = found_node.node;
-
-					    if node.node_id != own_id {
-						    iter.add_node(node);
+					    for node in found_node.nodes.into_iter() {
+						    if node.node_id != own_id {
+							    paths[path].add_node(node);
+						    }
 					    }
 				    },
-				    Message::FoundValue(FoundValue { sender_id: id, value: Value { data: v }, .. }) => {
-					    if !values.contains(&v) {
-				            //result_tx.send(v.clone()).unwrap();
-				            yield v.clone();
-				            values.insert(v);
+				    Message::FoundValue(FoundValue { sender_id: id, values: vs, .. }) => {
+					    for v in vs.into_iter() {
+						    if !values.contains(&v) {
+				                //result_tx.send(v.clone()).unwrap();
+				                yield v.clone();
+				                values.insert(v);
+						    }
 					    }
 
                         value_nodes.insert(id);
@@ -441,41 +1187,40 @@ impl Kademlia {
 
     #[async]
 	fn find_node(&self, key: NodeId) -> Result<Vec<Node>> {
-		let closest = self.kbuckets.get_nodes();
-
-		debug!("FindNode: {:?} initial nodes", closest.len());
-		let iter = ClosestNodesIter::new(key, K_PARAM, closest);
-
 		let req = Message::FindNode(FindNode {
 			cookie:    Self::generate_cookie(),
 			sender_id: self.get_own_id(),
 			key:       key,
 		});
-		let rx = self.server.send_many_request(iter.clone(), req, TIMEOUT_MS, ALPHA_PARAM); //chain channels??
-        let timeout = Sleep::new(Duration::from_millis(4*TIMEOUT_MS));
-
-        rx.select(timeout)
-          .inspect(|n| if node.node_id != own_id { iter.add_node(n) })
-          .take_while(|| is_not_timeout)
-          .fold(vec![], |nodes, n| Ok(nodes))
+		let (paths, rx) = self.send_disjoint(key, req);
+		debug!("FindNode: {} paths", paths.len());
 
+	    // the paths' results are merged, ordered by distance to the key
 	    let mut nodes_online = vec![];
 	    let mut failed = 0;
 	    while failed < TIMEOUT_MS/250 {
-		    for (sender, resp) in rx.iter() {
+		    for (path, sender, resp) in rx.iter() {
 			    debug!("resp={:?}", resp);
 			    failed = 0;
 
+			    if resp == Message::Timeout {
+				    self.kbuckets.record_failure(&sender);
+				    continue;
+			    }
+
                 if let Message::FoundNode(found_node) = resp {
-				    nodes_online.push(sender);
+				    if self.is_verified(&sender) {
+					    nodes_online.push(sender);
+				    }
 				    nodes_online.sort_by(asc_dist_order!(key));
 				    nodes_online.dedup();
 
 				    let own_id = self.get_own_id();
-				    let node = found_node.node;
 
-				    if node.node_id != own_id {
-					    iter.add_node(node);
+				    for node in found_node.nodes.into_iter() {
+					    if node.node_id != own_id {
+						    paths[path].add_node(node);
+					    }
 				    }
 			    };
 		    }
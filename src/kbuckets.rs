@@ -1,141 +1,484 @@
-use std::sync::{Arc,Mutex,MutexGuard};
+use std::sync::{Arc,Mutex};
 use std::net::{SocketAddr};
 use std::io;
+use std::fmt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
-use node::{Node, NodeId, NODEID_BYTELEN, xor};
+use node::{Node, NodeId, Liveness, NODEID_BYTELEN, xor};
 use kademlia::K_PARAM;
+use message::enc_id;
 
 #[cfg(test)]
 use utils::ignore;
+#[cfg(test)]
+use node::MAX_FAILURES;
+
+/// Recently seen nodes that did not fit into a full bucket
+pub const REPLACEMENT_CACHE_LEN: usize = K_PARAM;
+
+const MAX_DEPTH: usize = NODEID_BYTELEN*8;
+
+/// A leaf of the routing tree: holds the nodes whose ids start with the
+/// first `depth` bits of `prefix`
+pub struct Bucket {
+	prefix:       NodeId,
+	depth:        usize,
+	pub nodes:    Vec<Node>,
+	/// candidates for the next free slot, the most recently seen last
+	replacements: VecDeque<Node>,
+	/// the node we are pinging to see whether it is still alive
+	probing:      Option<Node>,
+	/// when a node was added or we looked up an id in this bucket
+	touched_at:   Instant,
+}
+
+impl Bucket {
+	fn new(prefix: NodeId, depth: usize) -> Bucket {
+		Bucket {
+			prefix:       prefix,
+			depth:        depth,
+			nodes:        vec![],
+			replacements: VecDeque::new(),
+			probing:      None,
+			touched_at:   Instant::now(),
+		}
+	}
+
+	/// A random id this bucket covers
+	fn random_id(&self) -> NodeId {
+		let random = Node::generate_id();
+		let keep = mask(&[0xff; NODEID_BYTELEN], self.depth);
+
+		let mut id = [0; NODEID_BYTELEN];
+		for i in 0..NODEID_BYTELEN {
+			id[i] = (self.prefix[i] & keep[i]) | (random[i] & !keep[i]);
+		}
+		id
+	}
+
+	fn covers(&self, id: &NodeId) -> bool {
+		mask(&xor(&self.prefix, id), self.depth) == [0; NODEID_BYTELEN]
+	}
+
+	/// Lower bound of the distance between `key` and any id in this bucket
+	fn min_dist(&self, key: &NodeId) -> NodeId {
+		mask(&xor(&self.prefix, key), self.depth)
+	}
+
+	/// Split into the half whose next bit is 0 and the half where it is 1
+	fn split(self) -> (Bucket, Bucket) {
+		let mut high_prefix = self.prefix;
+		high_prefix[self.depth / 8] |= 0x80 >> (self.depth % 8);
+
+		let mut low = Bucket::new(self.prefix, self.depth + 1);
+		let mut high = Bucket::new(high_prefix, self.depth + 1);
+		low.touched_at = self.touched_at;
+		high.touched_at = self.touched_at;
+
+		for n in self.nodes.into_iter() {
+			if low.covers(&n.node_id) { low.nodes.push(n) } else { high.nodes.push(n) }
+		}
+		for n in self.replacements.into_iter() {
+			if low.covers(&n.node_id) { low.replacements.push_back(n) } else { high.replacements.push_back(n) }
+		}
+		if let Some(n) = self.probing {
+			if low.covers(&n.node_id) { low.probing = Some(n) } else { high.probing = Some(n) }
+		}
 
+		(low, high)
+	}
+
+	fn cache_replacement(&mut self, node: Node) {
+		if let Some(pos) = self.replacements.iter().position(|n| *n == node) {
+			self.replacements.remove(pos);
+		}
+
+		self.replacements.push_back(node);
+		while self.replacements.len() > REPLACEMENT_CACHE_LEN {
+			self.replacements.pop_front();
+		}
+	}
+
+	fn least_recently_seen(&self) -> Option<Node> {
+		self.nodes.iter()
+			.min_by_key(|n| *n.last_seen.lock().unwrap())
+			.cloned()
+	}
+}
+
+/// Keep the first `depth` bits of `id`
+fn mask(id: &NodeId, depth: usize) -> NodeId {
+	let mut masked = [0; NODEID_BYTELEN];
+
+	for (i, b) in id.iter().enumerate() {
+		let bits = depth.saturating_sub(8*i).min(8);
+		masked[i] = if bits == 0 { 0 } else { b & !(0xffu8 >> bits) };
+	}
+	masked
+}
+
+/// The routing table: a binary tree over the id space whose leaves are
+/// buckets of up to `K_PARAM` nodes.
+///
+/// Only the bucket covering our own id is split when it is full, and
+/// buckets holding nodes among the `K_PARAM` closest to us (relaxed
+/// splitting), so the table knows our neighbourhood best.
 #[derive(Clone)]
 pub struct KBuckets {
 	own_id:  Arc<Mutex<NodeId>>,
-	buckets: Vec<Arc<Mutex<Vec<Node>>>>
+	/// the leaves of the tree, ordered by prefix
+	buckets: Arc<Mutex<Vec<Bucket>>>,
 }
 
 impl KBuckets {
 	pub fn new(own_id: Arc<Mutex<NodeId>>) -> KBuckets {
-		let buckets = (0..NODEID_BYTELEN*8)
-			.map(|_| Arc::new(Mutex::new(vec![])))
-			.collect();
-
 		KBuckets {
 			own_id:  own_id,
-			buckets: buckets,
+			buckets: Arc::new(Mutex::new(vec![Bucket::new([0; NODEID_BYTELEN], 0)])),
 		}
 	}
 
+	fn own_id(&self) -> NodeId {
+		self.own_id.lock().unwrap().clone()
+	}
+
 	pub fn construct_node(&mut self, addr: SocketAddr, node_id: NodeId) -> io::Result<Node> {
 		let default = try!(Node::new(addr, node_id));
-		let err = io::Error::new(io::ErrorKind::Other, "Hey, you stole my NodeId!");
 
-		match self.get_bucket(&node_id) {
-			None => Err(err),
-			Some(ref b) => {
-				let found = b.iter().find(|n| **n == default).map(|n| n.clone());
-				Ok(found.unwrap_or(default))
-			}
+		if node_id == self.own_id() {
+			return Err(io::Error::new(io::ErrorKind::Other, "Hey, you stole my NodeId!"));
 		}
+
+		let buckets = self.buckets.lock().unwrap();
+		let bucket = &buckets[bucket_idx(&buckets, &node_id)];
+
+		let found = bucket.nodes.iter().find(|n| **n == default).map(|n| n.clone());
+		Ok(found.unwrap_or(default))
 	}
 
-	fn get_bucket_idx(&self, node_id: &NodeId) -> Option<usize> {
-		let own_id = {
-			self.own_id.lock().unwrap()
-		};
+	pub fn estimate_peers_in_network(&self) -> usize {
+		let own_id = self.own_id();
+		let buckets = self.buckets.lock().unwrap();
 
-		for (i, x) in xor(&own_id, node_id).iter().enumerate() {
-			for j in (0..8).rev() {
-				let mask = 1<<j;
+		// our bucket covers 1/2^depth of the id space
+		let bucket = &buckets[bucket_idx(&buckets, &own_id)];
+		let len = bucket.nodes.len().max(1);
 
-				if x & mask == mask {
-					let idx = 8*(node_id.len()-1-i) + j;
-					return Some(idx);
+		1usize.checked_shl(bucket.depth as u32)
+			.and_then(|n| n.checked_mul(len))
+			.unwrap_or(usize::max_value())
+	}
+
+	pub fn add(&mut self, node: Node) -> Result<(), Node> {
+		let own_id = self.own_id();
+		if node.node_id == own_id {
+			return Ok(()); // ignore silently
+		}
+
+		let mut buckets = self.buckets.lock().unwrap();
+
+		loop {
+			let idx = bucket_idx(&buckets, &node.node_id);
+			{
+				let bucket = &mut buckets[idx];
+
+				if bucket.nodes.contains(&node) {
+					return Ok(());
+				}
+
+				if bucket.nodes.len() < K_PARAM {
+					bucket.nodes.push(node);
+					bucket.touched_at = Instant::now();
+					return Ok(());
+				}
+
+				// bad nodes make room first
+				if let Some(pos) = bucket.nodes.iter().position(|n| n.liveness() == Liveness::Bad) {
+					debug!("Replacing bad {:?} by {:?}", bucket.nodes[pos], node);
+					bucket.nodes[pos] = node;
+					bucket.touched_at = Instant::now();
+					return Ok(());
+				}
+
+				if bucket.depth == MAX_DEPTH {
+					return Err(node);
 				}
 			}
+
+			let split = buckets[idx].covers(&own_id)
+				|| is_among_closest(&buckets, &own_id, &node);
+			if !split {
+				return Err(node);
+			}
+
+			let (low, high) = buckets.remove(idx).split();
+			buckets.insert(idx, high);
+			buckets.insert(idx, low);
 		}
-		None
 	}
 
-	pub fn estimate_peers_in_network(&self) -> usize {
-        let mut estimate = 2;
+	/// Keep `node`, which did not fit into its full bucket, as a
+	/// replacement.
+	///
+	/// Returns the least recently seen node of the bucket, which the caller
+	/// should ping and report back via `probed()`. Returns None if such a
+	/// ping is on its way already.
+	pub fn cache_replacement(&mut self, node: Node) -> Option<Node> {
+		let mut buckets = self.buckets.lock().unwrap();
+		let idx = bucket_idx(&buckets, &node.node_id);
+		let bucket = &mut buckets[idx];
 
-        for (i, bucket) in self.buckets.iter().rev().enumerate() {
-            let len = bucket.lock().unwrap().len();
-            if len == 0 {
-                return estimate;
-            }
-            estimate = len * 2usize.pow(i as u32);
-        }
+		bucket.cache_replacement(node);
 
-        return estimate;
-	}
+		if bucket.probing.is_some() {
+			return None;
+		}
 
-	pub fn get_bucket(&self, node_id: &NodeId) -> Option<MutexGuard<Vec<Node>>> {
-		self.get_bucket_idx(&node_id)
-			.and_then(|i| self.buckets.get(i))
-			.map(|b| b.lock().unwrap())
+		bucket.probing = bucket.least_recently_seen();
+		bucket.probing.clone()
 	}
 
-	pub fn get_mut_bucket(&mut self, node_id: &NodeId) -> Option<MutexGuard<Vec<Node>>> {
-		self.get_bucket_idx(&node_id)
-			.and_then(move |i| self.buckets.get_mut(i))
-			.map(|b| b.lock().unwrap())
-	}
+	/// Whether the node returned by `cache_replacement()` answered.
+	///
+	/// Once it became bad, the most recently seen replacement takes its
+	/// slot.
+	pub fn probed(&mut self, node: &Node, alive: bool) {
+		let mut buckets = self.buckets.lock().unwrap();
+		let idx = bucket_idx(&buckets, &node.node_id);
+		let bucket = &mut buckets[idx];
 
-	pub fn add(&mut self, node: Node) -> Result<(), Node> {
-		match self.get_mut_bucket(&node.node_id) {
-			None => Ok(()), // ignore silently
-			Some(ref b) if b.contains(&node) => Ok(()),
-			Some(ref mut b) => {
-				if b.len() < K_PARAM {
-					b.push(node);
-					Ok(())
-				} else {
-					Err(node)
-				}
+		bucket.probing = None;
+
+		if alive {
+			return;
+		}
+
+		let pos = bucket.nodes.iter().position(|n| n == node);
+		if let Some(pos) = pos {
+			bucket.nodes[pos].record_failure();
+			if bucket.nodes[pos].liveness() != Liveness::Bad {
+				return;
 			}
+
+			bucket.nodes.remove(pos);
+
+			if let Some(replacement) = bucket.replacements.pop_back() {
+				debug!("Replacing {:?} by {:?}", node, replacement);
+				bucket.nodes.push(replacement);
+			}
+		}
+	}
+
+	/// `node` did not answer a request
+	pub fn record_failure(&self, node: &Node) {
+		let buckets = self.buckets.lock().unwrap();
+		let bucket = &buckets[bucket_idx(&buckets, &node.node_id)];
+
+		if let Some(n) = bucket.nodes.iter().find(|n| *n == node) {
+			n.record_failure();
 		}
 	}
 
+	/// Note that we looked up `id`, so its bucket does not need a refresh
+	pub fn touch(&self, id: &NodeId) {
+		let mut buckets = self.buckets.lock().unwrap();
+		let idx = bucket_idx(&buckets, id);
+		buckets[idx].touched_at = Instant::now();
+	}
+
+	/// A random id in each bucket that was not touched for `max_age`.
+	///
+	/// Look these ids up and `touch()` them afterwards to refresh the
+	/// buckets.
+	pub fn stale_ids(&self, max_age: Duration) -> Vec<NodeId> {
+		let buckets = self.buckets.lock().unwrap();
+
+		buckets.iter()
+			.filter(|b| b.touched_at.elapsed() >= max_age)
+			.map(|b| b.random_id())
+			.collect()
+	}
+
+	/// The `n` nodes closest to `key`, closest first.
+	///
+	/// Walks the buckets outwards from `key` and only sorts the nodes of
+	/// the buckets it needs. Bad nodes are skipped, questionable ones keep
+	/// their place: lookups must converge on the closest nodes.
 	pub fn get_closest_nodes(&self, key: &NodeId, n: usize) -> Vec<Node> {
-		let mut nodes = self.get_nodes();
+		let buckets = self.buckets.lock().unwrap();
 
-		nodes.sort_by(asc_dist_order!(key));
-		nodes.truncate(n);
+		let mut nodes = vec![];
+		for bucket in buckets_by_dist(&buckets, key).into_iter() {
+			if nodes.len() >= n {
+				break;
+			}
 
+			let mut bucket_nodes:Vec<Node> = bucket.nodes.iter()
+				.filter(|node| node.liveness() != Liveness::Bad)
+				.cloned()
+				.collect();
+			bucket_nodes.sort_by(asc_dist_order!(key));
+			nodes.extend(bucket_nodes);
+		}
+
+		nodes.truncate(n);
 		nodes
 	}
 
 	pub fn get_nodes(&self) -> Vec<Node> {
-		let append = |a:Vec<Node>, b:MutexGuard<Vec<Node>>| {
-			let res:Vec<Node> = a.into_iter().chain(b.clone().into_iter()).collect();
-			res
-		};
+		let buckets = self.buckets.lock().unwrap();
 
-		self.buckets.iter()
-			.map(|b| b.lock().unwrap())
-			.fold(vec![], append)
+		buckets.iter()
+			.flat_map(|b| b.nodes.iter().cloned())
+			.collect()
 	}
 }
 
+/// The routing table dump: one line per bucket followed by its nodes
+impl fmt::Debug for KBuckets {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let buckets = self.buckets.lock().unwrap();
+
+		for bucket in buckets.iter() {
+			let count = |l| bucket.nodes.iter().filter(|n| n.liveness() == l).count();
+
+			try!(writeln!(f, "Bucket {}/{}: {} nodes ({} good, {} questionable, {} bad), {} replacements",
+				enc_id(&bucket.prefix), bucket.depth, bucket.nodes.len(),
+				count(Liveness::Good), count(Liveness::Questionable), count(Liveness::Bad),
+				bucket.replacements.len()));
+
+			for node in bucket.nodes.iter() {
+				try!(writeln!(f, "  {:?}", node));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Index of the bucket covering `id`
+fn bucket_idx(buckets: &Vec<Bucket>, id: &NodeId) -> usize {
+	// the first bucket's prefix is all zeros, so there always is one
+	match buckets.binary_search_by(|b| b.prefix.cmp(id)) {
+		Ok(idx) => idx,
+		Err(idx) => idx - 1,
+	}
+}
+
+/// The non-empty buckets, the one closest to `key` first
+fn buckets_by_dist<'a>(buckets: &'a Vec<Bucket>, key: &NodeId) -> Vec<&'a Bucket> {
+	// the buckets' distance ranges do not overlap
+	let mut by_dist:Vec<(NodeId, &Bucket)> = buckets.iter()
+		.filter(|b| !b.nodes.is_empty())
+		.map(|b| (b.min_dist(key), b))
+		.collect();
+	by_dist.sort_by(|a, b| a.0.cmp(&b.0));
+
+	by_dist.into_iter().map(|(_, b)| b).collect()
+}
+
+fn closest_nodes(buckets: &Vec<Bucket>, key: &NodeId, n: usize) -> Vec<Node> {
+	let mut nodes = vec![];
+	for bucket in buckets_by_dist(buckets, key).into_iter() {
+		if nodes.len() >= n {
+			break;
+		}
+
+		let mut bucket_nodes = bucket.nodes.clone();
+		bucket_nodes.sort_by(asc_dist_order!(key));
+		nodes.extend(bucket_nodes);
+	}
+
+	nodes.truncate(n);
+	nodes
+}
+
+/// Whether `node` is closer to us than the `K_PARAM`th closest node we know
+fn is_among_closest(buckets: &Vec<Bucket>, own_id: &NodeId, node: &Node) -> bool {
+	let closest = closest_nodes(buckets, own_id, K_PARAM);
+
+	closest.len() < K_PARAM
+		|| closest.last().map_or(true, |n| node.dist(own_id) < n.dist(own_id))
+}
+
 #[test]
-fn test_get_bucket() {
-	let this = [0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		            0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00];
-	let nearest = [0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		               0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x01];
-	let farest  = [0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,
-		               0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff];
+fn test_split() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+
+	let nearest = {
+		let mut id = [0x00; NODEID_BYTELEN];
+		id[NODEID_BYTELEN-1] = 0x01;
+		id
+	};
+	let farthest = [0xff; NODEID_BYTELEN];
+
+	assert_eq!(mask(&farthest, 0), [0x00; NODEID_BYTELEN]);
+	assert_eq!(mask(&farthest, 12)[..3], [0xff, 0xf0, 0x00]);
+
+	ignore(b.add(Node::new("127.0.0.1:1", nearest).unwrap()));
+	ignore(b.add(Node::new("127.0.0.1:2", farthest).unwrap()));
+	for _ in 0..1000 {
+		ignore(b.add(Node::new("127.0.0.1:3", Node::generate_id()).unwrap()));
+	}
+
+	{
+		let buckets = b.buckets.lock().unwrap();
+		assert!(buckets.len() > 1);
+		assert!(buckets.iter().all(|b| b.nodes.len() <= K_PARAM));
+		assert!(buckets.iter().all(|b| b.nodes.iter().all(|n| b.covers(&n.node_id))));
+
+		// the leaves partition the id space
+		for (i, bucket) in buckets.iter().enumerate() {
+			assert_eq!(bucket_idx(&buckets, &bucket.prefix), i);
+		}
+	}
 
-	let b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
-	assert_eq!(b.get_bucket_idx(&this), None);
-	assert_eq!(b.get_bucket_idx(&nearest), Some(0));
-	assert_eq!(b.get_bucket_idx(&farest), Some(NODEID_BYTELEN*8-1));
+	// our neighbourhood is never dropped
+	let nodes = b.get_nodes();
+	assert!(nodes.iter().any(|n| n.node_id == nearest));
+	assert!(b.construct_node("127.0.0.1:4".parse().unwrap(), this).is_err());
 
-	assert!(b.get_bucket(&this).is_none());
-	assert!(b.get_bucket(&nearest).is_some());
-	assert!(b.get_bucket(&farest).is_some());
+	// walking the tree gives the same result as sorting every node
+	for _ in 0..20 {
+		let key = Node::generate_id();
+
+		let mut expected = nodes.clone();
+		expected.sort_by(asc_dist_order!(key));
+		expected.truncate(K_PARAM);
+
+		assert_eq!(b.get_closest_nodes(&key, K_PARAM), expected);
+	}
+}
+
+#[test]
+fn test_refresh() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+
+	for _ in 0..100 {
+		ignore(b.add(Node::new("127.0.0.1:1", Node::generate_id()).unwrap()));
+	}
+	let bucket_count = b.buckets.lock().unwrap().len();
+
+	// every bucket is fresh after the nodes were added
+	assert!(b.stale_ids(Duration::from_secs(60)).is_empty());
+
+	// a random id inside each bucket
+	let ids = b.stale_ids(Duration::from_secs(0));
+	assert_eq!(ids.len(), bucket_count);
+	{
+		let buckets = b.buckets.lock().unwrap();
+		for (bucket, id) in buckets.iter().zip(ids.iter()) {
+			assert!(bucket.covers(id));
+		}
+	}
+
+	// fresh again once they were looked up
+	for id in ids.iter() {
+		b.touch(id);
+	}
+	assert!(b.stale_ids(Duration::from_secs(60)).is_empty());
 }
 
 #[test]
@@ -151,3 +494,103 @@ fn test_get_nearest() {
 	let node_list = b.get_closest_nodes(&this, 10);
 	assert_eq!(node_list, vec![n]);
 }
+
+#[test]
+fn test_replacement_cache() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+
+	// all these nodes go into the farthest bucket
+	let node = |i: u8| {
+		let mut id = [0xff; NODEID_BYTELEN];
+		id[NODEID_BYTELEN-1] = i;
+		Node::new(("127.0.0.1", i as u16 + 1), id).unwrap()
+	};
+
+	for i in 0..K_PARAM {
+		assert!(b.add(node(i as u8)).is_ok());
+	}
+	let oldest = b.get_nodes().into_iter().min_by_key(|n| *n.last_seen.lock().unwrap()).unwrap();
+
+	let newcomer = node(K_PARAM as u8);
+	assert_eq!(b.add(newcomer.clone()), Err(newcomer.clone()));
+
+	// only one ping at a time
+	assert_eq!(b.cache_replacement(newcomer.clone()), Some(oldest.clone()));
+	assert_eq!(b.cache_replacement(node(K_PARAM as u8 + 1)), None);
+
+	// a live node keeps its slot
+	b.probed(&oldest, true);
+	assert!(b.get_nodes().contains(&oldest));
+
+	// a dead one is replaced by the most recently seen candidate once
+	// it failed too often
+	for _ in 0..MAX_FAILURES {
+		assert!(b.get_nodes().contains(&oldest));
+		assert_eq!(b.cache_replacement(newcomer.clone()), Some(oldest.clone()));
+		b.probed(&oldest, false);
+	}
+
+	let nodes = b.get_nodes();
+	assert_eq!(nodes.len(), K_PARAM);
+	assert!(!nodes.contains(&oldest));
+	assert!(nodes.contains(&newcomer));
+}
+
+#[test]
+fn test_liveness() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+
+	let node = |i: u8| {
+		let mut id = [0xff; NODEID_BYTELEN];
+		id[NODEID_BYTELEN-1] = i;
+		Node::new(("127.0.0.1", i as u16 + 1), id).unwrap()
+	};
+
+	for i in 0..K_PARAM {
+		assert!(b.add(node(i as u8)).is_ok());
+	}
+
+	// the closest node is questionable, the second closest bad
+	b.record_failure(&node(0));
+	for _ in 0..MAX_FAILURES {
+		b.record_failure(&node(1));
+	}
+
+	let closest = b.get_closest_nodes(&this, 3);
+	assert_eq!(closest, vec![node(0), node(2), node(3)]);
+
+	let closest = b.get_closest_nodes(&this, K_PARAM);
+	assert_eq!(closest.len(), K_PARAM - 1);
+	assert!(!closest.contains(&node(1)));
+
+	// the bad node makes room without a ping
+	let newcomer = node(K_PARAM as u8);
+	assert!(b.add(newcomer.clone()).is_ok());
+	assert!(!b.get_nodes().contains(&node(1)));
+
+	let dump = format!("{:?}", b);
+	assert!(dump.contains("19 good, 1 questionable, 0 bad"));
+}
+
+#[test]
+fn test_closest_questionable() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+
+	let mut close = [0x00; NODEID_BYTELEN];
+	close[NODEID_BYTELEN-1] = 0x01;
+	let close = Node::new("127.0.0.1:1", close).unwrap();
+	let far = Node::new("127.0.0.1:2", [0xff; NODEID_BYTELEN]).unwrap();
+
+	ignore(b.add(close.clone()));
+	ignore(b.add(far.clone()));
+	b.record_failure(&close);
+	assert_eq!(close.liveness(), Liveness::Questionable);
+	assert_eq!(far.liveness(), Liveness::Good);
+
+	// distance decides, not liveness
+	assert_eq!(b.get_closest_nodes(&this, 2), vec![close.clone(), far]);
+	assert_eq!(b.get_closest_nodes(&this, 1), vec![close]);
+}
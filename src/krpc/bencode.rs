@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+
+/// Nesting deeper than this is rejected by `decode()`
+const MAX_DEPTH: usize = 16;
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Bencode {
+	Int(i64),
+	Bytes(Vec<u8>),
+	List(Vec<Bencode>),
+	Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+	pub fn encode(&self) -> Vec<u8> {
+		let mut buf = vec![];
+		self.encode_to(&mut buf);
+		buf
+	}
+
+	fn encode_to(&self, buf: &mut Vec<u8>) {
+		match *self {
+			Bencode::Int(i) => buf.extend_from_slice(format!("i{}e", i).as_bytes()),
+			Bencode::Bytes(ref b) => {
+				buf.extend_from_slice(format!("{}:", b.len()).as_bytes());
+				buf.extend_from_slice(b);
+			},
+			Bencode::List(ref l) => {
+				buf.push(b'l');
+				for item in l.iter() {
+					item.encode_to(buf);
+				}
+				buf.push(b'e');
+			},
+			Bencode::Dict(ref d) => {
+				// BTreeMap keeps the keys sorted like bencode requires
+				buf.push(b'd');
+				for (key, value) in d.iter() {
+					Bencode::Bytes(key.clone()).encode_to(buf);
+					value.encode_to(buf);
+				}
+				buf.push(b'e');
+			},
+		}
+	}
+
+	/// Returns None unless `buf` is exactly one bencoded value
+	pub fn decode(buf: &[u8]) -> Option<Bencode> {
+		match decode_from(buf, 0) {
+			Some((value, rest)) if rest.is_empty() => Some(value),
+			_ => None,
+		}
+	}
+
+	pub fn get(&self, key: &str) -> Option<&Bencode> {
+		match *self {
+			Bencode::Dict(ref d) => d.get(key.as_bytes()),
+			_ => None,
+		}
+	}
+
+	pub fn as_bytes(&self) -> Option<&[u8]> {
+		match *self {
+			Bencode::Bytes(ref b) => Some(b),
+			_ => None,
+		}
+	}
+
+	pub fn as_int(&self) -> Option<i64> {
+		match *self {
+			Bencode::Int(i) => Some(i),
+			_ => None,
+		}
+	}
+
+	pub fn as_list(&self) -> Option<&[Bencode]> {
+		match *self {
+			Bencode::List(ref l) => Some(l),
+			_ => None,
+		}
+	}
+}
+
+/// Build a dict from string keys
+pub fn dict(items: Vec<(&str, Bencode)>) -> Bencode {
+	Bencode::Dict(items.into_iter().map(|(k, v)| (k.as_bytes().to_vec(), v)).collect())
+}
+
+fn decode_from(buf: &[u8], depth: usize) -> Option<(Bencode, &[u8])> {
+	if depth > MAX_DEPTH {
+		return None;
+	}
+
+	match buf.first() {
+		Some(&b'i') => {
+			let end = buf.iter().position(|&c| c == b'e')?;
+			let i = parse_int(&buf[1..end])?;
+			Some((Bencode::Int(i), &buf[end+1..]))
+		},
+		Some(&b'l') => {
+			let mut rest = &buf[1..];
+			let mut list = vec![];
+
+			while rest.first() != Some(&b'e') {
+				let (item, r) = decode_from(rest, depth + 1)?;
+				list.push(item);
+				rest = r;
+			}
+			Some((Bencode::List(list), &rest[1..]))
+		},
+		Some(&b'd') => {
+			let mut rest = &buf[1..];
+			let mut dict = BTreeMap::new();
+
+			while rest.first() != Some(&b'e') {
+				let (key, r) = decode_bytes(rest)?;
+				let (value, r) = decode_from(r, depth + 1)?;
+				dict.insert(key, value);
+				rest = r;
+			}
+			Some((Bencode::Dict(dict), &rest[1..]))
+		},
+		Some(_) => decode_bytes(buf).map(|(b, rest)| (Bencode::Bytes(b), rest)),
+		None => None,
+	}
+}
+
+fn decode_bytes(buf: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+	let colon = buf.iter().position(|&c| c == b':')?;
+	let len = parse_int(&buf[..colon])?;
+
+	let start = colon + 1;
+	if len < 0 || (buf.len() - start) < len as usize {
+		return None;
+	}
+
+	let end = start + len as usize;
+	Some((buf[start..end].to_vec(), &buf[end..]))
+}
+
+fn parse_int(digits: &[u8]) -> Option<i64> {
+	::std::str::from_utf8(digits).ok().and_then(|s| s.parse().ok())
+}
+
+#[test]
+fn roundtrip() {
+	let value = dict(vec![
+		("t", Bencode::Bytes(b"aa".to_vec())),
+		("y", Bencode::Bytes(b"q".to_vec())),
+		("a", dict(vec![("id", Bencode::Bytes(vec![0xff; 20]))])),
+		("l", Bencode::List(vec![Bencode::Int(-3), Bencode::Int(42)])),
+	]);
+
+	let buf = value.encode();
+	assert_eq!(&buf[..12], b"d1:ad2:id20:");
+	assert_eq!(Bencode::decode(&buf), Some(value));
+}
+
+#[test]
+fn malformed() {
+	assert_eq!(Bencode::decode(b"4:spam"), Some(Bencode::Bytes(b"spam".to_vec())));
+	assert_eq!(Bencode::decode(b"5:spam"), None);
+	assert_eq!(Bencode::decode(b"i12"), None);
+	assert_eq!(Bencode::decode(b"l4:spam"), None);
+	assert_eq!(Bencode::decode(b"4:spamjunk"), None);
+	assert_eq!(Bencode::decode(&vec![b'l'; 1000]), None);
+}
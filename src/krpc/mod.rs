@@ -0,0 +1,454 @@
+//! KRPC, the bencoded protocol of the BitTorrent Mainline DHT (BEP 5), so
+//! a node can join mainline and find peers there.
+//!
+//! Our messages map to these KRPC queries and their responses:
+//!
+//! - `Ping`/`Pong`: ping
+//! - `FindNode`/`FoundNode`: find_node
+//! - `FindValue`/`FoundValue`: get_peers, the values are compact addresses
+//! - `Store`/`Stored`: announce_peer, the value must be a compact address,
+//!   port 0 sets `implied_port`
+//!
+//! Write tokens are passed through, so announce_peer is checked like a
+//! native `Store`.
+//!
+//! `Listen`, `Remove` and `Conflict` have no counterpart and are not sent.
+//! BEP 44 values are not supported: our signatures and immutable keys do
+//! not cover the bencoded form that mainline nodes check.
+
+mod bencode;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use node::{Node, NodeId, CompactNodes, NODEID_BYTELEN};
+use node::{compact_addr, parse_compact_addr};
+use message::{self, Message, Value, Cookie, ErrorCode};
+use message::{Ping, Pong, FindNode, FoundNode, FindValue, FoundValue, Store, Stored};
+use protocol::{Codec, Envelope, DecodeError};
+
+use self::bencode::{Bencode, dict};
+
+/// How long we keep the transaction of a query around, a response may
+/// take several datagrams
+const TRANSACTION_SECS: u64 = 30;
+
+/// KRPC codec, see `KademliaBuilder::mainline()`
+pub struct Krpc {
+	/// transaction ids of the queries we received
+	transactions: Mutex<HashMap<Cookie, (Vec<u8>, Instant)>>,
+	/// keys of the announce_peer queries we sent, the replies only carry an id
+	announces:    Mutex<HashMap<Cookie, (NodeId, Instant)>>,
+}
+
+impl Krpc {
+	pub fn new() -> Krpc {
+		Krpc {
+			transactions: Mutex::new(HashMap::new()),
+			announces:    Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn query(&self, cookie: &Cookie, method: &str, args: Vec<(&str, Bencode)>) -> Vec<u8> {
+		dict(vec![
+			("t", bytes(cookie)),
+			("y", bytes(b"q")),
+			("q", bytes(method.as_bytes())),
+			("a", dict(args)),
+		]).encode()
+	}
+
+	fn response(&self, cookie: &Cookie, kind: &str, body: Bencode) -> Vec<u8> {
+		let t = self.transactions.lock().unwrap().get(cookie)
+			.map(|&(ref t, _)| t.clone())
+			.unwrap_or(cookie.to_vec());
+
+		dict(vec![
+			("t", Bencode::Bytes(t)),
+			("y", bytes(kind.as_bytes())),
+			(kind, body),
+		]).encode()
+	}
+
+	fn decode_query(&self, msg: &Bencode, t: &[u8], src: SocketAddr) -> Option<Message> {
+		let method = msg.get("q")?.as_bytes()?;
+		let args = msg.get("a")?;
+		let sender_id = to_id(args.get("id")?.as_bytes()?)?;
+		let cookie = Node::generate_id();
+
+		let message = if method == b"ping" {
+			Message::Ping(Ping {
+				sender_id: sender_id,
+				cookie:    cookie,
+			})
+		} else if method == b"find_node" {
+			Message::FindNode(FindNode {
+				sender_id: sender_id,
+				cookie:    cookie,
+				key:       to_id(args.get("target")?.as_bytes()?)?,
+			})
+		} else if method == b"get_peers" {
+			Message::FindValue(FindValue {
+				sender_id: sender_id,
+				cookie:    cookie,
+				key:       to_id(args.get("info_hash")?.as_bytes()?)?,
+			})
+		} else if method == b"announce_peer" {
+			let port = if args.get("implied_port").and_then(|p| p.as_int()) == Some(1) {
+				src.port()
+			} else {
+				match args.get("port")?.as_int()? {
+					p @ 1...65535 => p as u16,
+					_ => return None,
+				}
+			};
+
+			Message::Store(Store {
+				sender_id: sender_id,
+				cookie:    cookie,
+				key:       to_id(args.get("info_hash")?.as_bytes()?)?,
+				value:     Value::new(compact_addr(&SocketAddr::new(src.ip(), port))),
+				cas:       None,
+				token:     args.get("token")?.as_bytes()?.to_vec(),
+			})
+		} else {
+			return None;
+		};
+
+		remember(&mut self.transactions.lock().unwrap(), cookie, t.to_vec());
+		Some(message)
+	}
+
+	fn decode_response(&self, msg: &Bencode, t: &[u8]) -> Option<Message> {
+		let r = msg.get("r")?;
+		let sender_id = to_id(r.get("id")?.as_bytes()?)?;
+		// our queries use the whole cookie as transaction id
+		let cookie = to_id(t)?;
+
+		if let Some((key, _)) = self.announces.lock().unwrap().remove(&cookie) {
+			return Some(Message::Stored(Stored {
+				sender_id: sender_id,
+				cookie:    cookie,
+				key:       key,
+			}));
+		}
+
+		let token = r.get("token").and_then(|t| t.as_bytes()).unwrap_or(&[]).to_vec();
+
+		if let Some(values) = r.get("values").and_then(|v| v.as_list()) {
+			let values = values.iter()
+				.filter_map(|v| v.as_bytes())
+				.map(|v| Value::new(v.to_vec()))
+				.collect();
+
+			return Some(Message::FoundValue(FoundValue {
+				sender_id: sender_id,
+				cookie:    cookie,
+				values:    values,
+				token:     token,
+			}));
+		}
+
+		let nodes4 = r.get("nodes").and_then(|n| n.as_bytes());
+		let nodes6 = r.get("nodes6").and_then(|n| n.as_bytes());
+
+		if nodes4.is_none() && nodes6.is_none() {
+			return Some(Message::Pong(Pong {
+				sender_id: sender_id,
+				cookie:    cookie,
+			}));
+		}
+
+		let compact = CompactNodes {
+			v4: nodes4.unwrap_or(&[]).to_vec(),
+			v6: nodes6.unwrap_or(&[]).to_vec(),
+		};
+
+		Some(Message::FoundNode(FoundNode {
+			sender_id: sender_id,
+			cookie:    cookie,
+			nodes:     compact.decode(),
+			token:     token,
+		}))
+	}
+}
+
+impl Codec for Krpc {
+	fn encode(&self, message: &Message, _: SocketAddr) -> Option<Vec<u8>> {
+		let buf = match *message {
+			Message::Ping(ref ping) =>
+				self.query(&ping.cookie, "ping", vec![("id", bytes(&ping.sender_id))]),
+			Message::FindNode(ref find_node) =>
+				self.query(&find_node.cookie, "find_node", vec![
+					("id",     bytes(&find_node.sender_id)),
+					("target", bytes(&find_node.key)),
+				]),
+			Message::FindValue(ref find_value) =>
+				self.query(&find_value.cookie, "get_peers", vec![
+					("id",        bytes(&find_value.sender_id)),
+					("info_hash", bytes(&find_value.key)),
+				]),
+			Message::Store(ref store) => {
+				let port = parse_compact_addr(&store.value)?.port();
+				remember(&mut self.announces.lock().unwrap(), store.cookie, store.key);
+
+				self.query(&store.cookie, "announce_peer", vec![
+					("id",           bytes(&store.sender_id)),
+					("implied_port", Bencode::Int((port == 0) as i64)),
+					("info_hash",    bytes(&store.key)),
+					("port",         Bencode::Int(port as i64)),
+					("token",        Bencode::Bytes(store.token.clone())),
+				])
+			},
+			Message::Pong(ref pong) =>
+				self.response(&pong.cookie, "r", dict(vec![("id", bytes(&pong.sender_id))])),
+			Message::Stored(ref stored) =>
+				self.response(&stored.cookie, "r", dict(vec![("id", bytes(&stored.sender_id))])),
+			Message::FoundNode(ref found_node) => {
+				let compact = CompactNodes::encode(&found_node.nodes);
+
+				self.response(&found_node.cookie, "r", dict(vec![
+					("id",     bytes(&found_node.sender_id)),
+					("nodes",  Bencode::Bytes(compact.v4)),
+					("nodes6", Bencode::Bytes(compact.v6)),
+					("token",  Bencode::Bytes(found_node.token.clone())),
+				]))
+			},
+			Message::FoundValue(ref found_value) => {
+				let values = found_value.values.iter()
+					.map(|v| Bencode::Bytes(v.data.clone()))
+					.collect();
+
+				self.response(&found_value.cookie, "r", dict(vec![
+					("id",     bytes(&found_value.sender_id)),
+					("token",  Bencode::Bytes(found_value.token.clone())),
+					("values", Bencode::List(values)),
+				]))
+			},
+			Message::Error(ref error) => {
+				let body = Bencode::List(vec![
+					Bencode::Int(error_code(error.code)),
+					bytes(error.code.to_string().as_bytes()),
+				]);
+				self.response(&error.cookie, "e", body)
+			},
+			Message::Listen(_)
+			| Message::Remove(_)
+			| Message::Conflict(_)
+			| Message::Timeout => return None,
+		};
+
+		Some(buf)
+	}
+
+	fn decode(&self, buf: &[u8], src: SocketAddr) -> Result<Envelope, DecodeError> {
+		let msg = Bencode::decode(buf).ok_or(DecodeError::Unknown)?;
+		let t = msg.get("t").and_then(|t| t.as_bytes()).ok_or(DecodeError::Unknown)?;
+		let y = msg.get("y").and_then(|y| y.as_bytes()).unwrap_or(&[]);
+
+		// errors carry no NodeId, so we skip them
+		let message = if y == b"q" {
+			self.decode_query(&msg, t, src)
+		} else if y == b"r" {
+			self.decode_response(&msg, t)
+		} else {
+			None
+		};
+
+		let message = message.ok_or(DecodeError::Malformed)?;
+		if !message.is_valid() {
+			return Err(DecodeError::Malformed);
+		}
+
+		let mut envelope = Envelope::new(message);
+		// mainline nodes only understand what KRPC can express
+		envelope.capabilities = 0;
+		Ok(envelope)
+	}
+}
+
+fn error_code(code: ErrorCode) -> i64 {
+	match code {
+		ErrorCode::ValueTooLarge      => 205,
+		ErrorCode::BadSignature       => 206,
+		ErrorCode::UnsupportedVersion => 204,
+		ErrorCode::InvalidToken       => 203,
+		ErrorCode::QuotaExceeded
+		| ErrorCode::RateLimited      => 202,
+	}
+}
+
+/// Insert `value` and drop the entries older than `TRANSACTION_SECS`
+fn remember<T>(map: &mut HashMap<Cookie, (T, Instant)>, cookie: Cookie, value: T) {
+	let now = Instant::now();
+	map.retain(|_, &mut (_, t)| now.duration_since(t) < Duration::from_secs(TRANSACTION_SECS));
+	map.insert(cookie, (value, now));
+}
+
+fn to_id(buf: &[u8]) -> Option<NodeId> {
+	if buf.len() != NODEID_BYTELEN {
+		return None;
+	}
+
+	let mut id = [0u8; NODEID_BYTELEN];
+	id.copy_from_slice(buf);
+	Some(id)
+}
+
+fn bytes(buf: &[u8]) -> Bencode {
+	Bencode::Bytes(buf.to_vec())
+}
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+	s.parse().unwrap()
+}
+
+#[test]
+fn queries_roundtrip() {
+	let krpc = Krpc::new();
+
+	let ping = Message::Ping(Ping {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+	});
+	let buf = krpc.encode(&ping, addr("10.0.0.1:6881")).unwrap();
+	assert_eq!(&buf[..18], b"d1:ad2:id20:\x01\x01\x01\x01\x01\x01");
+
+	// the other node echoes the transaction id
+	let pong = dict(vec![
+		("t", bytes(&[0x02; 20])),
+		("y", bytes(b"r")),
+		("r", dict(vec![("id", bytes(&[0x03; 20]))])),
+	]);
+	let envelope = krpc.decode(&pong.encode(), addr("10.0.0.1:6881")).unwrap();
+	assert_eq!(envelope.message, Message::Pong(Pong {
+		sender_id: [0x03; 20],
+		cookie:    [0x02; 20],
+	}));
+	assert_eq!(envelope.capabilities, 0);
+
+	let remove = Message::Remove(message::Remove {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+		key:       [0x03; 20],
+		token:     vec![],
+	});
+	assert_eq!(krpc.encode(&remove, addr("10.0.0.1:6881")), None);
+}
+
+#[test]
+fn answers_echo_transaction_id() {
+	let krpc = Krpc::new();
+	let src = addr("10.0.0.1:6881");
+
+	let find_node = dict(vec![
+		("t", bytes(b"aa")),
+		("y", bytes(b"q")),
+		("q", bytes(b"find_node")),
+		("a", dict(vec![("id", bytes(&[0x01; 20])), ("target", bytes(&[0x04; 20]))])),
+	]);
+	let cookie = match krpc.decode(&find_node.encode(), src).unwrap().message {
+		Message::FindNode(f) => { assert_eq!(f.key, [0x04; 20]); f.cookie },
+		m => panic!("unexpected {:?}", m),
+	};
+
+	let nodes = vec![
+		Node::new("1.2.3.4:5", [0x05; 20]).unwrap(),
+		Node::new("[2001:db8::1]:6", [0x06; 20]).unwrap(),
+	];
+	let found_node = Message::FoundNode(FoundNode {
+		sender_id: [0x07; 20],
+		cookie:    cookie,
+		nodes:     nodes.clone(),
+		token:     b"token".to_vec(),
+	});
+
+	let resp = Bencode::decode(&krpc.encode(&found_node, src).unwrap()).unwrap();
+	assert_eq!(resp.get("t"), Some(&bytes(b"aa")));
+	assert_eq!(resp.get("r").and_then(|r| r.get("token")), Some(&bytes(b"token")));
+
+	let r = resp.get("r").unwrap();
+	assert_eq!(r.get("nodes").and_then(|n| n.as_bytes()).map(|n| n.len()), Some(26));
+
+	let nodes6 = r.get("nodes6").unwrap().as_bytes().unwrap();
+	assert_eq!(CompactNodes { v4: vec![], v6: nodes6.to_vec() }.decode(), vec![nodes[1].clone()]);
+}
+
+#[test]
+fn announce_peer() {
+	let krpc = Krpc::new();
+	let src = addr("10.0.0.1:6881");
+
+	let announce = dict(vec![
+		("t", bytes(b"aa")),
+		("y", bytes(b"q")),
+		("q", bytes(b"announce_peer")),
+		("a", dict(vec![
+			("id",        bytes(&[0x01; 20])),
+			("info_hash", bytes(&[0x04; 20])),
+			("port",      Bencode::Int(51413)),
+			("token",     bytes(b"token")),
+		])),
+	]);
+
+	// the token is checked like the one of a native Store
+	let cookie = match krpc.decode(&announce.encode(), src).unwrap().message {
+		Message::Store(store) => {
+			assert_eq!(store.key, [0x04; 20]);
+			assert_eq!(store.token, b"token".to_vec());
+			assert_eq!(parse_compact_addr(&store.value), Some(addr("10.0.0.1:51413")));
+			store.cookie
+		},
+		m => panic!("unexpected {:?}", m),
+	};
+
+	// both the ack and a rejection echo the transaction id
+	let stored = Message::Stored(Stored {
+		sender_id: [0x07; 20],
+		cookie:    cookie,
+		key:       [0x04; 20],
+	});
+	let resp = Bencode::decode(&krpc.encode(&stored, src).unwrap()).unwrap();
+	assert_eq!(resp.get("t"), Some(&bytes(b"aa")));
+	assert_eq!(resp.get("r").and_then(|r| r.get("id")), Some(&bytes(&[0x07; 20])));
+
+	let error = Message::Error(message::Error {
+		sender_id: [0x07; 20],
+		cookie:    cookie,
+		code:      ErrorCode::InvalidToken,
+	});
+	let resp = Bencode::decode(&krpc.encode(&error, src).unwrap()).unwrap();
+	assert_eq!(resp.get("t"), Some(&bytes(b"aa")));
+}
+
+#[test]
+fn announce_peer_reply() {
+	let krpc = Krpc::new();
+	let dst = addr("10.0.0.1:6881");
+
+	let store = Message::Store(Store {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+		key:       [0x04; 20],
+		value:     Value::new(compact_addr(&addr("0.0.0.0:0"))),
+		cas:       None,
+		token:     b"token".to_vec(),
+	});
+	let query = Bencode::decode(&krpc.encode(&store, dst).unwrap()).unwrap();
+	assert_eq!(query.get("a").and_then(|a| a.get("implied_port")), Some(&Bencode::Int(1)));
+
+	// the reply only carries an id, but it answers our announce_peer
+	let reply = dict(vec![
+		("t", bytes(&[0x02; 20])),
+		("y", bytes(b"r")),
+		("r", dict(vec![("id", bytes(&[0x03; 20]))])),
+	]);
+	assert_eq!(krpc.decode(&reply.encode(), dst).unwrap().message, Message::Stored(Stored {
+		sender_id: [0x03; 20],
+		cookie:    [0x02; 20],
+		key:       [0x04; 20],
+	}));
+}
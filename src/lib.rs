@@ -0,0 +1,80 @@
+#![feature(proc_macro, conservative_impl_trait, generators)]
+
+//! BulletinBoard is a general-purpose Distributed-Hash-Table based on Kademlia.
+//!
+//! ```ignore
+//! let kad = KademliaBuilder::new()
+//!     .listen("[::]:0")
+//!     .supernode("162.211.65.88:56789")
+//!     .bootstrap(handle)?;
+//!
+//! kad.put(key, b"value".to_vec())?;
+//! let values = kad.get(key);
+//! ```
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate bincode;
+
+extern crate rand;
+#[macro_use] extern crate log;
+extern crate env_logger;
+extern crate crypto;
+extern crate flate2;
+
+extern crate futures_await as futures;
+extern crate futures_timer;
+extern crate tokio_core;
+
+#[cfg(feature="dbus")]
+extern crate dbus_tokio;
+#[cfg(feature="dbus")]
+extern crate dbus;
+
+#[macro_use]
+mod node;
+mod utils;
+mod server;
+mod message;
+pub mod protocol;
+mod kademlia;
+mod kbuckets;
+mod closest_nodes_iter;
+mod storage;
+mod error;
+mod builder;
+mod mutable;
+mod immutable;
+mod watch;
+mod chunked;
+mod compressed;
+mod sealed;
+mod krpc;
+mod token;
+mod identity;
+pub mod transport;
+
+#[cfg(feature="dbus")]
+pub mod dbus_service;
+
+#[cfg(feature="fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
+#[cfg(test)]
+mod test;
+
+pub use kademlia::{Kademlia, Config, StoredValue};
+pub use watch::Watch;
+pub use kademlia::{K_PARAM, ALPHA_PARAM, TIMEOUT_MS, MAX_VALUE_LEN, MAX_FOUND_VALUES};
+pub use chunked::MAX_CHUNKED_VALUE_LEN;
+pub use compressed::MAX_DECOMPRESSED_LEN;
+pub use node::{Node, NodeId, Liveness, CompactNodes, NODEID_BYTELEN};
+pub use builder::KademliaBuilder;
+pub use transport::{Transport, SimulatedNetwork};
+pub use mutable::{Keypair, PublicKey, mutable_key};
+pub use immutable::immutable_key;
+pub use sealed::{AppKey, SEAL_OVERHEAD};
+pub use message::ErrorCode;
+pub use error::{Error, Result};
@@ -1,73 +1,44 @@
-#![feature(proc_macro, conservative_impl_trait, generators)]
+extern crate bulletinboard;
 
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
 extern crate bincode;
-
-extern crate rand;
 #[macro_use] extern crate log;
 extern crate env_logger;
-extern crate crypto;
 extern crate docopt;
-
 extern crate rustc_serialize;
 
-use bincode::{serialize, deserialize, Bounded};
-
 extern crate futures_await as futures;
 extern crate tokio_core;
 
-#[cfg(feature="dbus")]
-extern crate dbus_tokio;
-#[cfg(feature="dbus")]
-extern crate dbus;
-
-#[macro_use]
-mod node;
-mod utils;
-mod server;
-mod message;
-mod kademlia;
-mod kbuckets;
-mod closest_nodes_iter;
-mod storage;
-
-#[cfg(feature="dbus")]
-mod dbus_service;
-
-#[cfg(test)]
-mod test;
-
 use std::env;
-use std::thread::{spawn,sleep};
-use std::fs::File;
+use std::io;
+use std::fs::{self,File};
 use std::path::{PathBuf,Path};
 use std::io::{Write,Read};
 use std::net::SocketAddr;
 use std::time::Duration;
 
+use bincode::{serialize, deserialize, Bounded};
 use docopt::Docopt;
 
-use kademlia::Kademlia;
-use node::Node;
+use bulletinboard::{Kademlia, KademliaBuilder, CompactNodes, StoredValue, NodeId};
 
-use futures::Future;
 use futures::Stream;
 use tokio_core::reactor::Core;
-use tokio_core::reactor::Timeout;
 use tokio_core::reactor::Interval;
 
 #[cfg(feature="dbus")]
-use dbus_service::dbus;
+use bulletinboard::dbus_service::dbus;
 
 static USAGE: &'static str = "
-Usage: bulletinboard [-c <path>] [-l <listen_addr>] [-j <join_addr>...]
+Usage: bulletinboard [-c <path>] [-s <path>] [-m] [-z] [-l <listen_addr>] [-j <join_addr>...]
 
 Options:
     -h, --help                   Show this message.
     --version                    Show the version of rustc.
     -c, --config <path>          Set the path to the config file.
+    -s, --storage <path>         Keep values stored for other nodes in this directory.
+    -m, --mainline               Join the BitTorrent Mainline DHT instead.
+    -z, --compress               Compress the values we put if they get smaller.
     -l, --listen <listen_addr>   Listen on this address.
     -j, --join <join_addr>       Bootstrap using these addresses.
 ";
@@ -75,27 +46,75 @@ Options:
 #[derive(RustcDecodable, Debug)]
 struct Args {
 	flag_config:  Option<String>,
+	flag_storage: Option<String>,
+	flag_mainline: bool,
+	flag_compress: bool,
 	flag_listen:  Option<String>,
 	flag_join:     Vec<String>,
 	flag_version: bool,
 }
 
 #[cfg(not(feature="dbus"))]
-fn dbus(_: Kademlia, dbus_name: &'static str) {
+fn dbus(_: Kademlia, _: &'static str, core: &mut Core) {
+	core.run(futures::future::empty::<(), ()>()).unwrap();
 }
 
 fn load_config(cfg_path: &Path) -> Vec<SocketAddr> {
 	if let Ok(mut cfg_file) = File::open(cfg_path) {
-		let mut contents = String::new();
-		cfg_file.read_to_string(&mut contents).unwrap_or(0);
+		let mut contents = vec![];
+		cfg_file.read_to_end(&mut contents).unwrap_or(0);
+
+		let nodes:CompactNodes = deserialize(&contents[..]).unwrap_or_default();
+		nodes.decode().iter().map(|n| n.addr).collect()
+	} else {
+		vec![]
+	}
+}
+
+/// The values we Store()d are saved next to the config file
+fn values_path(cfg_path: &Path) -> PathBuf {
+	let mut name = cfg_path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+	name.push("_values");
+
+	cfg_path.with_file_name(name)
+}
+
+fn load_values(path: &Path) -> Vec<StoredValue> {
+	if let Ok(mut file) = File::open(path) {
+		let mut contents = vec![];
+		file.read_to_end(&mut contents).unwrap_or(0);
 
-		let nodes:Vec<Node> = deserialize(&contents[..].as_bytes()).unwrap_or(vec![]);
-		nodes.iter().map(|n| n.addr).collect()
+		deserialize(&contents[..]).unwrap_or(vec![])
 	} else {
 		vec![]
 	}
 }
 
+fn save_values(path: &Path, values: &Vec<StoredValue>) {
+	let contents = serialize(values, Bounded(10*1024*1024)).unwrap_or(Vec::new());
+
+	if let Err(e) = write_file(path, &contents[..]) {
+		warn!("Could not save stored values to {:?}: {}", path, e);
+	}
+}
+
+/// Write the whole file first, so a crash never leaves half of it behind
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let tmp = path.with_extension("tmp");
+	try!(File::create(&tmp).and_then(|mut f| f.write_all(contents)));
+	fs::rename(&tmp, path)
+}
+
+/// What identifies a change of the stored values (`expires_at` moves a
+/// little on every call)
+fn values_snapshot(values: &Vec<StoredValue>) -> Vec<(NodeId, Vec<u8>)> {
+	let mut snapshot:Vec<_> = values.iter()
+		.map(|v| (v.key, v.value.clone()))
+		.collect();
+	snapshot.sort();
+	snapshot
+}
+
 fn main() {
 	env_logger::init().unwrap();
 
@@ -116,37 +135,67 @@ fn main() {
 		.map(|s| format!("{}", s))
 		.chain(args.flag_join.into_iter())
 		.collect();
-	supernodes.push("[2a00:1630:66:2a9::6db4]:59056".to_string());
-	supernodes.push("162.211.65.88:56789".to_string());
-	supernodes.push("[2602:fff6:0001:2cf3:0000:0000:0000:1]:56790".to_string());
+	if args.flag_mainline {
+		supernodes.push("router.bittorrent.com:6881".to_string());
+		supernodes.push("dht.transmissionbt.com:6881".to_string());
+	} else {
+		supernodes.push("[2a00:1630:66:2a9::6db4]:59056".to_string());
+		supernodes.push("162.211.65.88:56789".to_string());
+		supernodes.push("[2602:fff6:0001:2cf3:0000:0000:0000:1]:56790".to_string());
+	}
 	supernodes.sort_by(|a,b| a.cmp(b));
 	supernodes.dedup();
 
-	let supernodes = supernodes.iter()
+	let supernodes:Vec<&str> = supernodes.iter()
 		.map(|s| &s[..])
 		.collect();
 	debug!("supernodes: {:?}", supernodes);
 
-	let core = Core::new().unwrap();
+	let mut core = Core::new().unwrap();
 	let handle = core.handle();
 
-	let kad = Kademlia::bootstrap(handle, &listen_addr[..], supernodes, None);
+	let mut builder = KademliaBuilder::new()
+		.listen(&listen_addr[..])
+		.supernodes(supernodes)
+		.mainline(args.flag_mainline)
+		.compress_values(args.flag_compress);
+
+	if let Some(ref dir) = args.flag_storage {
+		builder = builder.storage_dir(dir);
+	}
+
+	let mut kad = builder.bootstrap(handle.clone()).unwrap();
+
+	let values_path = values_path(&cfg_path);
+	kad.restore_stored_values(load_values(&values_path));
 
+	// save the values we store soon after they change
 	let this = kad.clone();
-	handle.spawn_fn(|| {
-		dbus(this, "org.manuel.BulletinBoard");
+	let mut saved = values_snapshot(&this.get_stored_values());
+	handle.spawn(Interval::new(Duration::from_secs(5), &handle).unwrap().for_each(move |_| {
+		let values = this.get_stored_values();
+		let snapshot = values_snapshot(&values);
+
+		if snapshot != saved {
+			save_values(&values_path, &values);
+			saved = snapshot;
+		}
+
 		Ok(())
-	});
+	}).map_err(|_| ()));
 
-	let future = Interval::new(Duration::from_secs(5*60), &handle).unwrap().for_each(move |_| {
-		let nodes = kad.get_nodes();
+	let this = kad.clone();
+	let cfg_path = cfg_path.to_path_buf();
+	handle.spawn(Interval::new(Duration::from_secs(5*60), &handle).unwrap().for_each(move |_| {
+		let nodes = CompactNodes::encode(&this.get_nodes());
 		let contents = serialize(&nodes, Bounded(100*1024)).unwrap_or(Vec::new());
 
-		if let Ok(mut cfg_file) = File::create(&cfg_path) {
-			cfg_file.write(&contents[..]).unwrap_or(0);
+		if let Err(e) = write_file(&cfg_path, &contents[..]) {
+			warn!("Could not save nodes to {:?}: {}", cfg_path, e);
 		}
 
 		Ok(())
-	});
-	core.run(future).unwrap();
+	}).map_err(|_| ()));
+
+	dbus(kad, "org.manuel.BulletinBoard", &mut core);
 }
@@ -1,10 +1,20 @@
 use std::fmt;
 use std::ops::Deref;
 
+use bincode::{serialize, deserialize_from, Bounded};
+
 use node::{Node, NodeId};
+use kademlia::{K_PARAM, MAX_VALUE_LEN, MAX_FOUND_VALUES};
+use mutable::{self, PublicKey, MAX_SALT_LEN, SIGNATURE_BYTELEN};
+use immutable;
+use token::{Token, MAX_TOKEN_LEN};
 
 pub const COOKIE_BYTELEN:usize = 160/8;
 
+/// Largest encoded message: a `Store` of a signed `MAX_VALUE_LEN` value
+/// fits with room to spare
+pub const MAX_MESSAGE_LEN: usize = MAX_VALUE_LEN + 512;
+
 pub type Cookie = [u8; COOKIE_BYTELEN];
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
@@ -17,6 +27,10 @@ pub enum Message {
 		FoundValue(FoundValue),
 		Store(Store),
 		Listen(Listen),
+		Conflict(Conflict),
+		Remove(Remove),
+		Error(Error),
+		Stored(Stored),
 		Timeout,
 }
 
@@ -31,6 +45,10 @@ impl Message {
 			Message::FoundValue(ref r) => Some(&r.cookie),
 			Message::Store(ref r) => Some(&r.cookie),
             Message::Listen(ref r) => Some(&r.cookie),
+			Message::Conflict(ref r) => Some(&r.cookie),
+			Message::Remove(ref r) => Some(&r.cookie),
+			Message::Error(ref r) => Some(&r.cookie),
+			Message::Stored(ref r) => Some(&r.cookie),
 			Message::Timeout => None,
 		}
 	}
@@ -45,9 +63,48 @@ impl Message {
 			Message::FoundValue(ref r) => Some(r.sender_id.clone()),
 			Message::Store(ref r) => Some(r.sender_id.clone()),
 			Message::Listen(ref r) => Some(r.sender_id.clone()),
+			Message::Conflict(ref r) => Some(r.sender_id.clone()),
+			Message::Remove(ref r) => Some(r.sender_id.clone()),
+			Message::Error(ref r) => Some(r.sender_id.clone()),
+			Message::Stored(ref r) => Some(r.sender_id.clone()),
 			Message::Timeout => None,
 		}
 	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		serialize(self, Bounded(MAX_MESSAGE_LEN as u64)).unwrap()
+	}
+
+	/// Returns None unless `buf` holds a valid message of at most
+	/// `MAX_MESSAGE_LEN` bytes, see `is_valid()`
+	pub fn decode(buf: &[u8]) -> Option<Message> {
+		if buf.len() > MAX_MESSAGE_LEN {
+			return None;
+		}
+
+		let mut reader = buf;
+		deserialize_from(&mut reader, Bounded(MAX_MESSAGE_LEN as u64)).ok()
+			.and_then(|msg: Message| if msg.is_valid() { Some(msg) } else { None })
+	}
+
+	/// Whether a peer may have sent this message: the lists and values
+	/// it carries are not larger than we would send ourselves
+	pub fn is_valid(&self) -> bool {
+		match *self {
+			Message::FoundNode(ref r) =>
+				r.nodes.len() <= K_PARAM && r.token.len() <= MAX_TOKEN_LEN,
+			Message::FoundValue(ref r) =>
+				r.values.len() <= MAX_FOUND_VALUES && r.values.iter().all(|v| v.is_valid())
+					&& r.token.len() <= MAX_TOKEN_LEN,
+			Message::Store(ref r) =>
+				r.value.is_valid() && r.token.len() <= MAX_TOKEN_LEN,
+			Message::Remove(ref r) =>
+				r.token.len() <= MAX_TOKEN_LEN,
+			// only the server makes these up
+			Message::Timeout => false,
+			_ => true,
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
@@ -85,18 +142,20 @@ pub struct Listen {
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct FoundNode {
-	pub sender_id:  NodeId,
-	pub cookie:     Cookie,
-	pub node_count: usize,
-	pub node:       Node,
+	pub sender_id: NodeId,
+	pub cookie:    Cookie,
+	#[serde(with="::node::compact")]
+	pub nodes:     Vec<Node>,
+	/// Lets the requester `Store` on the sender, see `token::WriteTokens`
+	pub token:     Token,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct FoundValue {
-	pub sender_id:   NodeId,
-	pub cookie:      Cookie,
-	pub value_count: usize,
-	pub value:       Value,
+	pub sender_id: NodeId,
+	pub cookie:    Cookie,
+	pub values:    Vec<Value>,
+	pub token:     Token,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
@@ -105,16 +164,127 @@ pub struct Store {
 	pub cookie:    Cookie,
 	pub key:       NodeId,
 	pub value:     Value,
+	/// Only store the signed value if the stored one has this sequence number
+	pub cas:       Option<u64>,
+	/// The token the receiver handed out in its last `FoundNode`/`FoundValue`
+	pub token:     Token,
+}
+
+/// Drop the value the sender stored under `key`
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Remove {
+	pub sender_id: NodeId,
+	pub cookie:    Cookie,
+	pub key:       NodeId,
+	/// Proves that the sender receives at its address, like `Store::token`
+	pub token:     Token,
+}
+
+/// Reply to a `Store` whose `cas` did not match or whose signed value
+/// is older than the stored one
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Conflict {
+	pub sender_id: NodeId,
+	pub cookie:    Cookie,
+	pub key:       NodeId,
+	pub seq:       u64,
+}
+
+/// Reply to a `Store` or `Remove` the receiver accepted
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Stored {
+	pub sender_id: NodeId,
+	pub cookie:    Cookie,
+	pub key:       NodeId,
+}
+
+/// Reply to a request the receiver rejected
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Error {
+	pub sender_id: NodeId,
+	pub cookie:    Cookie,
+	pub code:      ErrorCode,
+}
+
+/// Why a request was rejected
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ErrorCode {
+	/// The value exceeds `MAX_VALUE_LEN` bytes
+	ValueTooLarge,
+	/// The receiver does not store any more values for the sender
+	QuotaExceeded,
+	/// The value does not match its key or signature
+	BadSignature,
+	/// The receiver speaks another protocol version
+	UnsupportedVersion,
+	/// The sender sent too many requests, try again later
+	RateLimited,
+	/// The write token is missing, expired or was issued to another address
+	InvalidToken,
+}
+
+impl fmt::Display for ErrorCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match *self {
+			ErrorCode::ValueTooLarge      => "value too large",
+			ErrorCode::QuotaExceeded      => "quota exceeded",
+			ErrorCode::BadSignature       => "bad signature",
+			ErrorCode::UnsupportedVersion => "unsupported protocol version",
+			ErrorCode::RateLimited        => "rate limited",
+			ErrorCode::InvalidToken       => "invalid write token",
+		};
+		write!(f, "{}", s)
+	}
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Hash)]
 pub struct Value {
-	pub data: Vec<u8>
+	pub data:      Vec<u8>,
+	pub signature: Option<Signature>,
+	/// The key is the hash of `data`, see `immutable::immutable_key()`
+	pub immutable: bool,
+}
+
+/// Signature of a mutable value, see `mutable::Keypair::sign()`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Hash)]
+pub struct Signature {
+	pub public_key: PublicKey,
+	pub salt:       Vec<u8>,
+	pub seq:        u64,
+	pub sig:        Vec<u8>,
 }
 
 impl Value {
 	pub fn new(data: Vec<u8>) -> Value {
-		Value { data: data }
+		Value {
+			data:      data,
+			signature: None,
+			immutable: false,
+		}
+	}
+
+	/// Whether the value is not larger than `MAX_VALUE_LEN` and its
+	/// signature fields fit `mutable`'s limits. Says nothing about whether
+	/// the signature is correct, see `verify()`.
+	pub fn is_valid(&self) -> bool {
+		self.data.len() <= MAX_VALUE_LEN && self.signature.as_ref().map_or(true, |s|
+			s.salt.len() <= MAX_SALT_LEN && s.sig.len() <= SIGNATURE_BYTELEN
+		)
+	}
+
+	pub fn seq(&self) -> Option<u64> {
+		self.signature.as_ref().map(|s| s.seq)
+	}
+
+	/// Plain values are always valid, signed ones must be signed by
+	/// the owner of `key` and immutable ones must hash to `key`
+	pub fn verify(&self, key: &NodeId) -> bool {
+		match (self.immutable, &self.signature) {
+			(false, &None) => true,
+			(false, &Some(ref signature)) => mutable::verify(key, &self.data, signature),
+			(true, &None) => immutable::verify(key, &self.data),
+			(true, &Some(_)) => false,
+		}
 	}
 }
 
@@ -138,8 +308,36 @@ pub fn enc_vec(id: &Vec<u8>) -> String {
 
 impl fmt::Debug for Store {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "sender={}, cookie={}, key: {}, value_len: {}",
-			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key), &self.value.data.len())
+		write!(f, "sender={}, cookie={}, key: {}, value_len: {}, cas: {:?}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key), &self.value.data.len(), self.cas)
+	}
+}
+
+impl fmt::Debug for Remove {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, key={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key))
+	}
+}
+
+impl fmt::Debug for Conflict {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, key={}, seq={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key), self.seq)
+	}
+}
+
+impl fmt::Debug for Stored {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, key={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key))
+	}
+}
+
+impl fmt::Debug for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, code={:?}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.code)
 	}
 }
 
@@ -174,14 +372,15 @@ impl fmt::Debug for FindValue {
 impl fmt::Debug for FoundNode {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		write!(f, "sender={}, cookie={}, count={} {:?}",
-			enc_id(&self.sender_id), enc_id(&self.cookie), self.node_count, self.node)
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.nodes.len(), self.nodes)
 	}
 }
 
 impl fmt::Debug for FoundValue {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "sender={}, cookie={}, count={} {}",
-			enc_id(&self.sender_id), enc_id(&self.cookie), self.value_count, enc_vec(&self.value))
+		let values:Vec<String> = self.values.iter().map(|v| enc_vec(v)).collect();
+		write!(f, "sender={}, cookie={}, count={} {:?}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.values.len(), values)
 	}
 }
 
@@ -192,3 +391,34 @@ impl fmt::Debug for Listen {
 	}
 }
 
+#[test]
+fn decode() {
+	let found_node = |count| Message::FoundNode(FoundNode {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+		nodes:     (0..count).map(|i| Node::new(("10.0.0.1", i as u16), [i; 20]).unwrap()).collect(),
+		token:     vec![0; 8],
+	});
+
+	assert_eq!(Message::decode(&found_node(K_PARAM as u8).encode()), Some(found_node(K_PARAM as u8)));
+	assert_eq!(Message::decode(&found_node(K_PARAM as u8 + 1).encode()), None);
+
+	let found_value = |count| Message::FoundValue(FoundValue {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+		values:    (0..count).map(|i| Value::new(vec![i as u8])).collect(),
+		token:     vec![0; 8],
+	});
+
+	assert_eq!(Message::decode(&found_value(MAX_FOUND_VALUES).encode()), Some(found_value(MAX_FOUND_VALUES)));
+	assert_eq!(Message::decode(&found_value(MAX_FOUND_VALUES + 1).encode()), None);
+
+	let mut value = Value::new(vec![0; MAX_VALUE_LEN]);
+	assert!(value.is_valid());
+	value.data.push(0);
+	assert!(!value.is_valid());
+
+	assert_eq!(Message::decode(&Message::Timeout.encode()), None);
+	assert_eq!(Message::decode(&[]), None);
+	assert_eq!(Message::decode(&vec![0; MAX_MESSAGE_LEN + 1]), None);
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+
+use bincode::{serialize, Bounded};
+use crypto::ed25519;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rand;
+
+use node::{NodeId, NODEID_BYTELEN};
+use message::{Value, Signature};
+use kademlia::MAX_VALUE_LEN;
+
+pub const PUBLIC_KEY_BYTELEN: usize = 32;
+pub const SIGNATURE_BYTELEN:  usize = 64;
+pub const MAX_SALT_LEN:       usize = 64;
+
+pub type PublicKey = [u8; PUBLIC_KEY_BYTELEN];
+
+/// An Ed25519 keypair that owns mutable values
+#[derive(Clone)]
+pub struct Keypair {
+	secret: Vec<u8>,
+	public: PublicKey,
+}
+
+impl Keypair {
+	pub fn generate() -> Keypair {
+		let seed:Vec<u8> = (0..32).map(|_| rand::random::<u8>()).collect();
+		Self::from_seed(&seed)
+	}
+
+	pub fn from_seed(seed: &[u8]) -> Keypair {
+		let (secret, public) = ed25519::keypair(seed);
+
+		Keypair {
+			secret: secret.to_vec(),
+			public: public,
+		}
+	}
+
+	pub fn public_key(&self) -> PublicKey {
+		self.public
+	}
+
+	/// Sign `data` as version `seq` of the value under `mutable_key(public_key, salt)`
+	///
+	/// Panics if `salt` exceeds `MAX_SALT_LEN` or `data` exceeds `MAX_VALUE_LEN` bytes.
+	pub fn sign(&self, salt: &[u8], seq: u64, data: Vec<u8>) -> Value {
+		let payload = signed_payload(salt, seq, &data).expect("salt or value too large to sign");
+		let sig = ed25519::signature(&payload, &self.secret);
+
+		let signature = Signature {
+			public_key: self.public,
+			salt:       salt.to_vec(),
+			seq:        seq,
+			sig:        sig.to_vec(),
+		};
+
+		Value {
+			data:      data,
+			signature: Some(signature),
+			immutable: false,
+		}
+	}
+}
+
+impl fmt::Debug for Keypair {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Keypair {{ public={} }}", enc_key(&self.public))
+	}
+}
+
+/// The DHT key of a mutable value is SHA1(public_key + salt) like in BEP 44
+pub fn mutable_key(public_key: &PublicKey, salt: &[u8]) -> NodeId {
+	let mut hasher = Sha1::new();
+
+	let mut output = vec![0x0; hasher.output_bytes()];
+	hasher.input(public_key);
+	hasher.input(salt);
+	hasher.result(&mut output[..]);
+
+	let mut key = [0u8; NODEID_BYTELEN];
+	key.clone_from_slice(&output[..NODEID_BYTELEN]);
+	key
+}
+
+/// Check that a signed value was signed by the owner of `key`
+pub fn verify(key: &NodeId, data: &[u8], signature: &Signature) -> bool {
+	if signature.salt.len() > MAX_SALT_LEN || signature.sig.len() != SIGNATURE_BYTELEN {
+		return false;
+	}
+
+	if mutable_key(&signature.public_key, &signature.salt) != *key {
+		return false;
+	}
+
+	match signed_payload(&signature.salt, signature.seq, data) {
+		Some(payload) => ed25519::verify(&payload, &signature.public_key, &signature.sig),
+		None          => false,
+	}
+}
+
+/// None if `salt` and `data` do not fit into the bound
+fn signed_payload(salt: &[u8], seq: u64, data: &[u8]) -> Option<Vec<u8>> {
+	let limit = (MAX_SALT_LEN + MAX_VALUE_LEN + 64) as u64;
+	serialize(&(salt, seq, data), Bounded(limit)).ok()
+}
+
+fn enc_key(key: &PublicKey) -> String {
+	let start:String = key[..3].iter().map(|x| format!("{:02x}", x)).collect();
+	start + "..."
+}
+
+#[test]
+fn sign_and_verify() {
+	let keypair = Keypair::from_seed(&[0x42; 32]);
+	let salt = b"salt";
+	let key = mutable_key(&keypair.public_key(), salt);
+
+	let value = keypair.sign(salt, 1, vec![1,2,3]);
+	assert!(value.verify(&key));
+
+	let other_key = mutable_key(&keypair.public_key(), b"pepper");
+	assert!(!value.verify(&other_key));
+
+	let mut forged = value.clone();
+	forged.data = vec![4,5,6];
+	assert!(!forged.verify(&key));
+
+	let mut replayed = value.clone();
+	replayed.signature.as_mut().unwrap().seq = 2;
+	assert!(!replayed.verify(&key));
+
+	// a payload over the bound is rejected instead of verified as empty
+	let mut oversized = value.clone();
+	oversized.data = vec![0; MAX_VALUE_LEN + MAX_SALT_LEN + 64];
+	assert!(!oversized.verify(&key));
+}
@@ -1,18 +1,25 @@
 use std::io;
 use std::fmt;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::sync::{Arc,Mutex};
-use std::net::{SocketAddr,ToSocketAddrs};
+use std::net::{SocketAddr,ToSocketAddrs,IpAddr,Ipv4Addr,Ipv6Addr};
 
 #[cfg(not(test))]
 use std::net::{SocketAddrV4,SocketAddrV6};
 
 use rand;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
 use utils;
 use message::enc_id;
 
 pub const NODEID_BYTELEN:usize = 160/8;
 
+/// A node is good if it answered within this time, see `Liveness`
+pub const GOOD_SECS: u64 = 15*60;
+/// Number of requests in a row a node failed to answer until it is bad
+pub const MAX_FAILURES: u32 = 3;
+
 pub type NodeId = [u8; NODEID_BYTELEN];
 
 macro_rules! asc_dist_order {
@@ -27,6 +34,20 @@ fn now_mutex() -> Arc<Mutex<Instant>> {
 	Arc::new(Mutex::new(Instant::now()))
 }
 
+fn zero_mutex() -> Arc<Mutex<u32>> {
+	Arc::new(Mutex::new(0))
+}
+
+/// Whether we expect a node to answer, like in BEP 5
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Liveness {
+	/// We heard of it within `GOOD_SECS` and it did not miss a request since
+	Good,
+	Questionable,
+	/// It failed to answer `MAX_FAILURES` requests in a row
+	Bad,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Node {
 	pub addr:      SocketAddr,
@@ -34,6 +55,10 @@ pub struct Node {
 	#[serde(skip_serializing)]
 	#[serde(skip_deserializing,default="now_mutex")]
 	pub last_seen: Arc<Mutex<Instant>>,
+	/// requests it failed to answer since `last_seen`
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="zero_mutex")]
+	pub failures:  Arc<Mutex<u32>>,
 }
 
 impl Node {
@@ -53,6 +78,7 @@ impl Node {
 			addr:      addr,
 			node_id:   node_id,
 			last_seen: Arc::new(Mutex::new(Instant::now())),
+			failures:  zero_mutex(),
 		};
 
 		Ok(node)
@@ -69,6 +95,25 @@ impl Node {
 	pub fn update_last_seen(&mut self) {
 		let mut last_seen = self.last_seen.lock().unwrap();
 		*last_seen = Instant::now();
+		*self.failures.lock().unwrap() = 0;
+	}
+
+	/// It did not answer a request
+	pub fn record_failure(&self) {
+		*self.failures.lock().unwrap() += 1;
+	}
+
+	pub fn liveness(&self) -> Liveness {
+		let failures = *self.failures.lock().unwrap();
+		let last_seen = *self.last_seen.lock().unwrap();
+
+		if failures >= MAX_FAILURES {
+			Liveness::Bad
+		} else if failures == 0 && last_seen.elapsed() < Duration::from_secs(GOOD_SECS) {
+			Liveness::Good
+		} else {
+			Liveness::Questionable
+		}
 	}
 
 	/// TODO: replace by rust stdlib methods, as soon as they become stable
@@ -168,11 +213,127 @@ impl Node {
 impl fmt::Debug for Node {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 	    let secs = self.last_seen.lock().unwrap().elapsed().as_secs() as f64;
-		write!(f, "Node {{ {}, id={}, last_seen={:.*}min ago }}",
-			self.addr, enc_id(&self.node_id), 2, secs/60.0)
+		write!(f, "Node {{ {}, id={}, last_seen={:.*}min ago, {:?}, failures={} }}",
+			self.addr, enc_id(&self.node_id), 2, secs/60.0,
+			self.liveness(), *self.failures.lock().unwrap())
 	}
 }
 
+/// The 4- or 16-byte IP address followed by the 2-byte port
+pub fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+	let mut buf = ip_bytes(&addr.ip());
+	buf.push((addr.port() >> 8) as u8);
+	buf.push(addr.port() as u8);
+	buf
+}
+
+pub fn parse_compact_addr(buf: &[u8]) -> Option<SocketAddr> {
+	let ip = match buf.len() {
+		6 => IpAddr::V4(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])),
+		18 => {
+			let mut octets = [0u8; 16];
+			octets.copy_from_slice(&buf[..16]);
+			IpAddr::V6(Ipv6Addr::from(octets))
+		},
+		_ => return None,
+	};
+
+	let port = (buf[buf.len()-2] as u16) << 8 | buf[buf.len()-1] as u16;
+	Some(SocketAddr::new(ip, port))
+}
+
+pub fn ip_bytes(ip: &IpAddr) -> Vec<u8> {
+	match *ip {
+		IpAddr::V4(ip) => ip.octets().to_vec(),
+		IpAddr::V6(ip) => ip.octets().to_vec(),
+	}
+}
+
+/// Nodes as a 20-byte id followed by their compact address, IPv4 and
+/// IPv6 nodes in separate lists like `nodes` and `nodes6` in BEP 5
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct CompactNodes {
+	pub v4: Vec<u8>,
+	pub v6: Vec<u8>,
+}
+
+impl CompactNodes {
+	pub fn encode(nodes: &[Node]) -> CompactNodes {
+		let mut compact = CompactNodes::default();
+
+		for node in nodes.iter() {
+			let buf = if node.addr.is_ipv4() { &mut compact.v4 } else { &mut compact.v6 };
+			buf.extend_from_slice(&node.node_id);
+			buf.extend_from_slice(&compact_addr(&node.addr));
+		}
+
+		compact
+	}
+
+	/// Skips truncated entries
+	pub fn decode(&self) -> Vec<Node> {
+		let mut nodes = parse_compact_nodes(&self.v4, 4);
+		nodes.extend(parse_compact_nodes(&self.v6, 16));
+		nodes
+	}
+}
+
+fn parse_compact_nodes(buf: &[u8], ip_len: usize) -> Vec<Node> {
+	let len = NODEID_BYTELEN + ip_len + 2;
+
+	buf.chunks(len)
+		.filter(|c| c.len() == len)
+		.filter_map(|c| {
+			let (id, addr) = c.split_at(NODEID_BYTELEN);
+
+			let mut node_id = [0u8; NODEID_BYTELEN];
+			node_id.copy_from_slice(id);
+
+			parse_compact_addr(addr).map(|addr| Node {
+				addr:      utils::ip4or6(addr),
+				node_id:   node_id,
+				last_seen: now_mutex(),
+				failures:  zero_mutex(),
+			})
+		})
+		.collect()
+}
+
+/// Serialize a `Vec<Node>` as `CompactNodes`, use with
+/// `#[serde(with="node::compact")]`
+pub mod compact {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(nodes: &Vec<Node>, serializer: S) -> Result<S::Ok, S::Error> {
+		CompactNodes::encode(nodes).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Node>, D::Error> {
+		CompactNodes::deserialize(deserializer).map(|c| c.decode())
+	}
+}
+
+#[test]
+fn liveness() {
+	let mut node = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	assert_eq!(node.liveness(), Liveness::Good);
+
+	node.record_failure();
+	assert_eq!(node.liveness(), Liveness::Questionable);
+
+	for _ in 1..MAX_FAILURES {
+		node.record_failure();
+	}
+	assert_eq!(node.liveness(), Liveness::Bad);
+
+	// clones share the state, like the copies in the routing table do
+	node.clone().update_last_seen();
+	assert_eq!(node.liveness(), Liveness::Good);
+
+	*node.last_seen.lock().unwrap() -= Duration::from_secs(GOOD_SECS);
+	assert_eq!(node.liveness(), Liveness::Questionable);
+}
+
 #[test]
 fn dist() {
 	let node = Node::new("127.0.0.1:2134", [
@@ -218,3 +379,26 @@ fn asc_order() {
 	nodes.sort_by(desc_dist_order!(id0x00));
 	assert_eq!(nodes, vec![node0xff, node0x00]);
 }
+
+#[test]
+fn compact_encoding() {
+	for a in ["1.2.3.4:6881", "[2001:db8::1]:443"].iter() {
+		let addr:SocketAddr = a.parse().unwrap();
+		assert_eq!(parse_compact_addr(&compact_addr(&addr)), Some(addr));
+	}
+	assert_eq!(parse_compact_addr(&[1, 2, 3]), None);
+
+	let nodes = vec![
+		Node::new("1.2.3.4:5", [0x05; 20]).unwrap(),
+		Node::new("[2001:db8::1]:6", [0x06; 20]).unwrap(),
+		Node::new("5.6.7.8:9", [0x07; 20]).unwrap(),
+	];
+
+	let compact = CompactNodes::encode(&nodes);
+	assert_eq!(compact.v4.len(), 2*26);
+	assert_eq!(compact.v6.len(), 38);
+	assert_eq!(compact.decode(), vec![nodes[0].clone(), nodes[2].clone(), nodes[1].clone()]);
+
+	let truncated = CompactNodes { v4: compact.v4[..30].to_vec(), v6: vec![] };
+	assert_eq!(truncated.decode(), vec![nodes[0].clone()]);
+}
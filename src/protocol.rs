@@ -0,0 +1,339 @@
+//! Every datagram is an `Envelope`: a fixed header with the protocol
+//! version and the sender's capabilities, followed by the `Message` and a
+//! list of extensions.
+//!
+//! Nodes with another protocol version are skipped. To add an optional
+//! field without bumping the version, send it as an `Extension` with a new
+//! id: nodes that do not know the id just ignore it.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use bincode::{serialize, deserialize_from, serialized_size, Bounded};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use message::{Message, MAX_MESSAGE_LEN};
+use mutable::PublicKey;
+use identity::{self, Identity};
+
+pub const MAGIC: [u8; 2] = *b"BB";
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Responses are packed to fit the minimum IPv6 MTU minus IP and UDP headers
+pub const MAX_DATAGRAM_LEN: usize = 1232;
+
+/// Largest datagram we send or accept: a message of `MAX_MESSAGE_LEN`
+/// plus header and extensions
+pub const MAX_ENVELOPE_LEN: usize = MAX_MESSAGE_LEN + 512;
+
+/// Answers `Listen` requests
+pub const CAP_LISTEN:    u32 = 1 << 0;
+/// Stores signed mutable values
+pub const CAP_MUTABLE:   u32 = 1 << 1;
+/// Stores immutable values
+pub const CAP_IMMUTABLE: u32 = 1 << 2;
+/// Answers `Store` requests with `cas` by `Conflict`
+pub const CAP_CAS:       u32 = 1 << 3;
+/// Handles `Remove` requests
+pub const CAP_REMOVE:    u32 = 1 << 4;
+
+/// Extension signing the message, see `Envelope::signer()`
+pub const EXT_IDENTITY: u16 = 1;
+
+/// What this node supports
+pub const CAPABILITIES: u32 = CAP_LISTEN | CAP_MUTABLE | CAP_IMMUTABLE | CAP_CAS | CAP_REMOVE;
+
+/// The part of the envelope every version of the protocol can decode
+#[derive(Serialize, Deserialize)]
+struct Header {
+	magic:        [u8; 2],
+	version:      u8,
+	capabilities: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Extension {
+	pub id:   u16,
+	pub data: Vec<u8>,
+}
+
+/// Data of `EXT_IDENTITY`
+#[derive(Serialize, Deserialize)]
+struct IdentityProof {
+	public_key: PublicKey,
+	sig:        Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Envelope {
+	magic:            [u8; 2],
+	pub version:      u8,
+	pub capabilities: u32,
+	pub message:      Message,
+	pub extensions:   Vec<Extension>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+	/// Not an envelope at all (e.g. a node older than protocol version 1)
+	Unknown,
+	/// An envelope of another protocol version
+	Incompatible(u8),
+	Malformed,
+}
+
+impl Envelope {
+	pub fn new(message: Message) -> Envelope {
+		Envelope {
+			magic:        MAGIC,
+			version:      PROTOCOL_VERSION,
+			capabilities: CAPABILITIES,
+			message:      message,
+			extensions:   vec![],
+		}
+	}
+
+	pub fn supports(&self, capability: u32) -> bool {
+		self.capabilities & capability == capability
+	}
+
+	/// Sign the message with `identity`
+	pub fn sign(&mut self, identity: &Identity) {
+		let proof = IdentityProof {
+			public_key: identity.public_key(),
+			sig:        identity.sign(&message_bytes(&self.message)),
+		};
+
+		self.extensions.retain(|e| e.id != EXT_IDENTITY);
+		self.extensions.push(Extension {
+			id:   EXT_IDENTITY,
+			data: serialize(&proof, Bounded(256)).unwrap(),
+		});
+	}
+
+	/// The public key that signed the message, if any.
+	///
+	/// `decode()` drops envelopes with invalid signatures, so the sender
+	/// owns this key. It may only use the NodeId `identity::node_id_of()`
+	/// this key.
+	pub fn signer(&self) -> Option<PublicKey> {
+		self.identity_proof().map(|p| p.public_key)
+	}
+
+	fn identity_proof(&self) -> Option<IdentityProof> {
+		self.extensions.iter()
+			.find(|e| e.id == EXT_IDENTITY)
+			.and_then(|e| bounded_deserialize(&e.data).ok())
+	}
+}
+
+fn message_bytes(message: &Message) -> Vec<u8> {
+	message.encode()
+}
+
+/// Turns messages into datagrams and back, see `Native` and `krpc::Krpc`
+pub trait Codec: Send + Sync {
+	/// Returns None if `message` cannot be expressed in this codec
+	fn encode(&self, message: &Message, dst: SocketAddr) -> Option<Vec<u8>>;
+	fn decode(&self, buf: &[u8], src: SocketAddr) -> Result<Envelope, DecodeError>;
+}
+
+/// Our own protocol, see `encode()` and `decode()`
+pub struct Native {
+	identity: Arc<RwLock<Identity>>,
+}
+
+impl Native {
+	/// Messages are signed with `identity`
+	pub fn new(identity: Arc<RwLock<Identity>>) -> Native {
+		Native {
+			identity: identity,
+		}
+	}
+}
+
+impl Codec for Native {
+	fn encode(&self, message: &Message, _: SocketAddr) -> Option<Vec<u8>> {
+		let mut envelope = Envelope::new(message.clone());
+		envelope.sign(&self.identity.read().unwrap());
+
+		Some(serialize(&envelope, Bounded(MAX_ENVELOPE_LEN as u64)).unwrap())
+	}
+
+	fn decode(&self, buf: &[u8], _: SocketAddr) -> Result<Envelope, DecodeError> {
+		decode(buf)
+	}
+}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+	serialize(&Envelope::new(message.clone()), Bounded(MAX_ENVELOPE_LEN as u64)).unwrap()
+}
+
+/// Never reads more than `MAX_ENVELOPE_LEN` bytes and rejects messages
+/// that are not `Message::is_valid()`
+pub fn decode(buf: &[u8]) -> Result<Envelope, DecodeError> {
+	if buf.len() > MAX_ENVELOPE_LEN {
+		return Err(DecodeError::Malformed);
+	}
+
+	let header:Header = try!(bounded_deserialize(buf).map_err(|_| DecodeError::Unknown));
+
+	if header.magic != MAGIC {
+		return Err(DecodeError::Unknown);
+	}
+
+	if header.version != PROTOCOL_VERSION {
+		return Err(DecodeError::Incompatible(header.version));
+	}
+
+	let envelope:Envelope = try!(bounded_deserialize(buf).map_err(|_| DecodeError::Malformed));
+
+	if !envelope.message.is_valid() {
+		return Err(DecodeError::Malformed);
+	}
+
+	let has_proof = envelope.extensions.iter().any(|e| e.id == EXT_IDENTITY);
+	match envelope.identity_proof() {
+		Some(ref proof) if identity::verify(&proof.public_key, &message_bytes(&envelope.message), &proof.sig) => (),
+		None if !has_proof => (),
+		_ => return Err(DecodeError::Malformed),
+	}
+
+	Ok(envelope)
+}
+
+fn bounded_deserialize<T: DeserializeOwned>(buf: &[u8]) -> ::bincode::Result<T> {
+	let mut reader = buf;
+	deserialize_from(&mut reader, Bounded(MAX_ENVELOPE_LEN as u64))
+}
+
+/// Split `items` into lists that each fit into one datagram next to
+/// `overhead` bytes of envelope and message. An item too large for the
+/// budget is sent in a datagram of its own.
+pub fn pack<T: Serialize>(items: Vec<T>, overhead: usize) -> Vec<Vec<T>> {
+	let mut packets = vec![];
+	let mut packet = vec![];
+	let mut len = overhead;
+
+	for item in items.into_iter() {
+		let size = serialized_size(&item) as usize;
+
+		if !packet.is_empty() && len + size > MAX_DATAGRAM_LEN {
+			packets.push(packet);
+			packet = vec![];
+			len = overhead;
+		}
+
+		len += size;
+		packet.push(item);
+	}
+
+	if !packet.is_empty() {
+		packets.push(packet);
+	}
+	packets
+}
+
+#[cfg(test)]
+fn ping() -> Message {
+	use message::Ping;
+
+	Message::Ping(Ping {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+	})
+}
+
+#[test]
+fn roundtrip() {
+	let envelope = decode(&encode(&ping())).unwrap();
+
+	assert_eq!(envelope.message, ping());
+	assert_eq!(envelope.version, PROTOCOL_VERSION);
+	assert!(envelope.supports(CAP_LISTEN | CAP_REMOVE));
+}
+
+#[test]
+fn unknown_extensions_are_kept() {
+	let mut envelope = Envelope::new(ping());
+	envelope.extensions.push(Extension { id: 0xffff, data: vec![1,2,3] });
+
+	let buf = serialize(&envelope, Bounded(MAX_ENVELOPE_LEN as u64)).unwrap();
+	assert_eq!(decode(&buf), Ok(envelope));
+}
+
+#[test]
+fn incompatible() {
+	let mut envelope = Envelope::new(ping());
+	envelope.version = PROTOCOL_VERSION + 1;
+	let buf = serialize(&envelope, Bounded(MAX_ENVELOPE_LEN as u64)).unwrap();
+	assert_eq!(decode(&buf), Err(DecodeError::Incompatible(PROTOCOL_VERSION + 1)));
+
+	// messages of nodes before the envelope was introduced
+	let buf = serialize(&ping(), Bounded(MAX_ENVELOPE_LEN as u64)).unwrap();
+	assert_eq!(decode(&buf), Err(DecodeError::Unknown));
+
+	let mut buf = encode(&ping());
+	buf.truncate(10);
+	assert_eq!(decode(&buf), Err(DecodeError::Malformed));
+}
+
+#[test]
+fn packing() {
+	let items:Vec<Vec<u8>> = (0..20).map(|_| vec![0; 100]).collect();
+	let packets = pack(items, 32);
+
+	// 108 bytes per item
+	assert_eq!(packets.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![11, 9]);
+
+	let packets = pack(vec![vec![0u8; 10], vec![0; 2000], vec![0; 10]], 32);
+	assert_eq!(packets.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![1, 1, 1]);
+
+	assert!(pack(Vec::<u8>::new(), 32).is_empty());
+}
+
+#[test]
+fn signed() {
+	let identity = Identity::generate(0);
+
+	let mut envelope = Envelope::new(ping());
+	envelope.sign(&identity);
+
+	let buf = serialize(&envelope, Bounded(MAX_ENVELOPE_LEN as u64)).unwrap();
+	assert_eq!(decode(&buf).unwrap().signer(), Some(identity.public_key()));
+	assert_eq!(decode(&encode(&ping())).unwrap().signer(), None);
+
+	// the signature does not match another message
+	let mut forged = envelope.clone();
+	forged.message = Message::Timeout;
+	let buf = serialize(&forged, Bounded(MAX_ENVELOPE_LEN as u64)).unwrap();
+	assert_eq!(decode(&buf), Err(DecodeError::Malformed));
+}
+
+#[test]
+fn bounds() {
+	use message::{Store, Value};
+	use kademlia::MAX_VALUE_LEN;
+
+	let store = |len| Message::Store(Store {
+		sender_id: [0x01; 20],
+		cookie:    [0x02; 20],
+		key:       [0x03; 20],
+		value:     Value::new(vec![0; len]),
+		cas:       Some(1),
+		token:     vec![0; 8],
+	});
+
+	let envelope = decode(&encode(&store(MAX_VALUE_LEN))).unwrap();
+	assert_eq!(envelope.message, store(MAX_VALUE_LEN));
+
+	// nobody sends values this large
+	let envelope = Envelope::new(store(MAX_VALUE_LEN + 1));
+	let buf = serialize(&envelope, Bounded(MAX_ENVELOPE_LEN as u64)).unwrap();
+	assert_eq!(decode(&buf), Err(DecodeError::Malformed));
+
+	let mut buf = encode(&ping());
+	buf.extend(vec![0; MAX_ENVELOPE_LEN]);
+	assert_eq!(decode(&buf), Err(DecodeError::Malformed));
+}
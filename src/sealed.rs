@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crypto::sha2::Sha256;
+use crypto::hkdf::{hkdf_extract, hkdf_expand};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use rand;
+
+use node::NodeId;
+
+const KEY_BYTELEN:   usize = 32;
+const SALT_BYTELEN:  usize = 16;
+const NONCE_BYTELEN: usize = 8;
+const TAG_BYTELEN:   usize = 16;
+
+/// Number of bytes a sealed value is larger than the plain one
+pub const SEAL_OVERHEAD: usize = SALT_BYTELEN + TAG_BYTELEN;
+
+/// Symmetric key to encrypt and authenticate the values of one application.
+///
+/// Nodes that do not know the secret can neither read these values nor
+/// forge them.
+#[derive(Clone)]
+pub struct AppKey {
+	key: [u8; KEY_BYTELEN],
+}
+
+impl AppKey {
+	/// Derive the key of `app_id` from a secret shared by its users
+	pub fn derive(app_id: &str, secret: &[u8]) -> AppKey {
+		let mut prk = [0u8; KEY_BYTELEN];
+		hkdf_extract(Sha256::new(), app_id.as_bytes(), secret, &mut prk);
+
+		let mut key = [0u8; KEY_BYTELEN];
+		hkdf_expand(Sha256::new(), &prk, b"bulletinboard sealed value", &mut key);
+
+		AppKey {
+			key: key,
+		}
+	}
+
+	/// Encrypt `data` stored under the DHT key `key`.
+	///
+	/// The DHT key is authenticated as well, so a sealed value cannot be
+	/// copied to another key.
+	pub fn seal(&self, key: &NodeId, data: &[u8]) -> Vec<u8> {
+		let salt:Vec<u8> = (0..SALT_BYTELEN).map(|_| rand::random::<u8>()).collect();
+
+		let mut ciphertext = vec![0; data.len()];
+		let mut tag = [0u8; TAG_BYTELEN];
+
+		let mut cipher = ChaCha20Poly1305::new(&self.value_key(&salt), &[0; NONCE_BYTELEN], key);
+		cipher.encrypt(data, &mut ciphertext, &mut tag);
+
+		let mut sealed = salt;
+		sealed.extend_from_slice(&ciphertext);
+		sealed.extend_from_slice(&tag);
+		sealed
+	}
+
+	/// Returns None if `sealed` was not sealed by this key for `key`
+	pub fn open(&self, key: &NodeId, sealed: &[u8]) -> Option<Vec<u8>> {
+		if sealed.len() < SEAL_OVERHEAD {
+			return None;
+		}
+
+		let (salt, rest) = sealed.split_at(SALT_BYTELEN);
+		let (ciphertext, tag) = rest.split_at(rest.len() - TAG_BYTELEN);
+
+		let mut data = vec![0; ciphertext.len()];
+
+		let mut cipher = ChaCha20Poly1305::new(&self.value_key(salt), &[0; NONCE_BYTELEN], key);
+		if cipher.decrypt(ciphertext, &mut data, tag) {
+			Some(data)
+		} else {
+			None
+		}
+	}
+
+	/// Every value is sealed with its own key derived from a random salt,
+	/// so the all-zero nonce is never used twice with the same key
+	fn value_key(&self, salt: &[u8]) -> [u8; KEY_BYTELEN] {
+		let mut prk = [0u8; KEY_BYTELEN];
+		hkdf_extract(Sha256::new(), salt, &self.key, &mut prk);
+
+		let mut key = [0u8; KEY_BYTELEN];
+		hkdf_expand(Sha256::new(), &prk, b"bulletinboard sealed value key", &mut key);
+		key
+	}
+}
+
+impl fmt::Debug for AppKey {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "AppKey {{ .. }}")
+	}
+}
+
+#[test]
+fn seal_and_open() {
+	let app_key = AppKey::derive("mytestapp", b"secret");
+	let key = [0x00; 20];
+
+	let sealed = app_key.seal(&key, b"bar");
+	assert_eq!(sealed.len(), 3 + SEAL_OVERHEAD);
+	assert_eq!(app_key.open(&key, &sealed), Some(b"bar".to_vec()));
+
+	// another key
+	assert_eq!(app_key.open(&[0xff; 20], &sealed), None);
+
+	// another secret or app
+	assert_eq!(AppKey::derive("mytestapp", b"guess").open(&key, &sealed), None);
+	assert_eq!(AppKey::derive("otherapp", b"secret").open(&key, &sealed), None);
+
+	// tampered
+	let mut forged = sealed.clone();
+	forged[SALT_BYTELEN] ^= 0x01;
+	assert_eq!(app_key.open(&key, &forged), None);
+
+	// another salt derives another key
+	let mut forged = sealed.clone();
+	forged[0] ^= 0x01;
+	assert_eq!(app_key.open(&key, &forged), None);
+
+	// the same value is sealed with a fresh key every time
+	assert!(app_key.seal(&key, b"bar") != sealed);
+
+	assert_eq!(app_key.open(&key, &[1,2,3]), None);
+}
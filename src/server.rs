@@ -1,51 +1,151 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread::{spawn,sleep};
 use std::sync::mpsc::{Sender,Receiver,channel};
 use std::sync::{Arc,Mutex};
 use std::str;
 use std::io;
 use std::net::{SocketAddr};
-use std::collections::HashMap;
-
-use bincode::{serialize, deserialize, Bounded};
+use std::collections::{HashMap, HashSet};
 
 use futures::prelude::*;
 use futures::Future;
+use futures::sync::mpsc::{UnboundedSender,UnboundedReceiver,unbounded};
 use tokio_core::reactor::Handle;
 use tokio_core::reactor::Timeout;
-use tokio_core::net::UdpSocket;
-use tokio_core::net::UdpFramed;
 
 use utils::ignore;
 use utils;
 use utils::semaphore::Semaphore;
-use message::{Message, Cookie};
-use node::Node;
-
+use message::{self, Message, Cookie, ErrorCode};
+use node::{Node, NodeId};
+use identity;
+use transport::Transport;
+use protocol::{Codec, DecodeError};
+
+/// We warn about a peer of another protocol version once per hour
+const INCOMPATIBLE_SECS: u64 = 60*60;
+/// and remember at most this many of them
+const MAX_INCOMPATIBLE_PEERS: usize = 1024;
+
+#[derive(Clone)]
 pub struct Server {
-	handle: Handle,
+	pub handle: Handle,
 	pub local_addr: SocketAddr,
-	sink: SplitSink<UdpFramed<Codec>>,
-	stream:  SplitStream<UdpFramed<Codec>>,
-	pending_requests: Rc<RefCell<HashMap<(SocketAddr, Cookie), Sender<Message>>>>
+	sock: Arc<Transport>,
+	codec: Arc<Codec>,
+	pending_requests: Arc<Mutex<HashMap<(SocketAddr, Cookie), Sender<Message>>>>,
+	subscriptions: Arc<Mutex<HashMap<Cookie, UnboundedSender<(SocketAddr, Message)>>>>,
+	capabilities: Arc<Mutex<HashMap<SocketAddr, u32>>>,
+	verified_ids: Arc<Mutex<HashMap<SocketAddr, NodeId>>>,
+	own_id: Arc<Mutex<NodeId>>,
+	incompatible_peers: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
 }
 
 // TODO: cleanup 'pending_requests' from time to time!
 
 impl Server {
-	pub fn new(handle: Handle, sock: UdpSocket) -> Result<Server> {
-		info!("Listening on {:?}", sock.local_addr());
+	/// `own_id` is shared with `Kademlia`, so replies we make up carry our current NodeId
+	pub fn new(handle: Handle, sock: Arc<Transport>, codec: Arc<Codec>, own_id: Arc<Mutex<NodeId>>)
+		-> io::Result<Server>
+	{
 		let local_addr = sock.local_addr()?;
-		let sink, stream = sock.framed(Codec).split();
-		Server {
+		info!("Listening on {:?}", local_addr);
+
+		Ok(Server {
 			handle,
 			local_addr,
-			sink,
-			stream
-			pending_requests: Rc::new(RefCell::new(HashMap::new())),
+			sock,
+			codec,
+			pending_requests: Arc::new(Mutex::new(HashMap::new())),
+			subscriptions: Arc::new(Mutex::new(HashMap::new())),
+			capabilities: Arc::new(Mutex::new(HashMap::new())),
+			verified_ids: Arc::new(Mutex::new(HashMap::new())),
+			own_id,
+			incompatible_peers: Arc::new(Mutex::new(HashMap::new())),
+		})
+	}
+
+	/// The capabilities (see `protocol::CAP_*`) the node at `addr` announced
+	/// in its last message
+	pub fn capabilities(&self, addr: &SocketAddr) -> Option<u32> {
+		let capabilities = self.capabilities.lock().unwrap();
+		capabilities.get(addr).cloned()
+	}
+
+	/// answer our pending requests to `addr` with an `Error`, e.g. because
+	/// we cannot understand its responses anyway
+	fn reject_pending(&self, addr: &SocketAddr, code: ErrorCode) {
+		let own_id = *self.own_id.lock().unwrap();
+		let pending = self.pending_requests.lock().unwrap();
+
+		for (&(_, cookie), tx) in pending.iter().filter(|&(&(a, _), _)| a == *addr) {
+			ignore(tx.send(Message::Error(message::Error {
+				sender_id: own_id,
+				cookie:    cookie,
+				code:      code,
+			})));
 		}
 	}
 
+	/// Returns true if we did not hear from the incompatible peer at `addr`
+	/// within the last `INCOMPATIBLE_SECS`
+	fn remember_incompatible(&self, addr: SocketAddr) -> bool {
+		let mut incompatible = self.incompatible_peers.lock().unwrap();
+		let now = Instant::now();
+
+		if incompatible.get(&addr).map_or(false, |t| now.duration_since(*t).as_secs() < INCOMPATIBLE_SECS) {
+			return false;
+		}
+
+		incompatible.retain(|_, t| now.duration_since(*t).as_secs() < INCOMPATIBLE_SECS);
+		if incompatible.len() < MAX_INCOMPATIBLE_PEERS {
+			incompatible.insert(addr, now);
+		}
+		true
+	}
+
+	/// The NodeId the node at `addr` proved to own by signing its last
+	/// response to one of our requests, see `protocol::Envelope::signer()`.
+	///
+	/// Only responses count: they carry the cookie of our request, so unlike
+	/// a signed request they cannot be replayed from another address.
+	pub fn verified_id(&self, addr: &SocketAddr) -> Option<NodeId> {
+		let verified_ids = self.verified_ids.lock().unwrap();
+		verified_ids.get(addr).cloned()
+	}
+
+	/// forget the verified ids of all nodes but the ones at `addrs`
+	pub fn retain_verified_ids(&self, addrs: &HashSet<SocketAddr>) {
+		let mut verified_ids = self.verified_ids.lock().unwrap();
+		verified_ids.retain(|addr, _| addrs.contains(addr));
+	}
+
+	fn is_pending(&self, addr: SocketAddr, msg: &Message) -> bool {
+		let pending = self.pending_requests.lock().unwrap();
+		msg.cookie().map_or(false, |cookie| pending.contains_key(&(addr, *cookie)))
+	}
+
+	/// returns a Stream of all responses carrying `cookie` no matter who
+	/// sent them (e.g. values pushed because of a `Listen`)
+	pub fn subscribe(&self, cookie: Cookie) -> UnboundedReceiver<(SocketAddr, Message)> {
+		let (tx, rx) = unbounded();
+
+		let mut subscriptions = self.subscriptions.lock().unwrap();
+		subscriptions.insert(cookie, tx);
+
+		rx
+	}
+
+	pub fn unsubscribe(&self, cookie: &Cookie) {
+		let mut subscriptions = self.subscriptions.lock().unwrap();
+		subscriptions.remove(cookie);
+	}
+
+	pub fn is_subscribed(&self, cookie: &Cookie) -> bool {
+		let subscriptions = self.subscriptions.lock().unwrap();
+		subscriptions.contains_key(cookie)
+	}
+
 	/// just send a message and don't care about the reponse
 	pub fn hit_and_run(&self, addr: SocketAddr, req: &Message) {
 		self.send(addr, req);
@@ -69,16 +169,21 @@ impl Server {
 			(*pending).insert(key, tx);
 		}
 
-		let buf = serialize(&req, Bounded(2048)).unwrap();
-		self.sock.send_to(&buf[..], addr).unwrap();
+		self.send_to(addr, req);
 
 		rx
 	}
 
 	pub fn send_response(&self, addr: SocketAddr, resp: &Message)
 	{
-		let buf = serialize(&resp, Bounded(2048)).unwrap();
-		self.sock.send_to(&buf[..], addr).unwrap();
+		self.send_to(addr, resp);
+	}
+
+	fn send_to(&self, addr: SocketAddr, msg: &Message) {
+		match self.codec.encode(msg, addr) {
+			Some(buf) => ignore(self.sock.send_to(&buf[..], addr)),
+			None => debug!("Cannot send {:?} to {:?} with this codec", msg, addr),
+		}
 	}
 
 	pub fn send_request_ms(&self, addr: &SocketAddr, req: &Message, timeout: u32)
@@ -93,8 +198,7 @@ impl Server {
 		}
 
 		debug!("Sending {:?} to {:?}", req, addr);
-		let buf = serialize(&req, Bounded(2048)).unwrap();
-		ignore(self.sock.send_to(&buf[..], addr));
+		self.send_to(*addr, req);
 
 		let handle = self.handle.clone();
 		handle.spawn_fn(move || {
@@ -173,7 +277,30 @@ impl Iterator for Server {
 			let src = utils::ip4or6(src);
 			let msg = &buf[..len];
 
-			let msg:Result<Message,_> = deserialize(msg);
+			let msg = match self.codec.decode(msg, src) {
+				Ok(envelope) => {
+					let mut capabilities = self.capabilities.lock().unwrap();
+					capabilities.insert(src, envelope.capabilities);
+
+					if self.is_pending(src, &envelope.message) {
+						let mut verified_ids = self.verified_ids.lock().unwrap();
+						match envelope.signer() {
+							Some(public_key) => verified_ids.insert(src, identity::node_id_of(&public_key)),
+							None => verified_ids.remove(&src),
+						};
+					}
+
+					Ok(envelope.message)
+				},
+				Err(DecodeError::Incompatible(version)) => {
+					if self.remember_incompatible(src) {
+						warn!("Skipping {}: it speaks protocol version {}", src, version);
+					}
+					self.reject_pending(&src, ErrorCode::UnsupportedVersion);
+					continue;
+				},
+				Err(err) => Err(err),
+			};
 
 			debug!("got {:?}", msg);
 
@@ -184,12 +311,16 @@ impl Iterator for Server {
 				| Ok(Message::FindValue(_))
 				| Ok(Message::Store(_))
 				| Ok(Message::Listen(_))
+				| Ok(Message::Remove(_))
 				| Ok(Message::Timeout)
 				| Err(_) => (),
 
 				Ok(ref resp @ Message::Pong(_))
 				| Ok(ref resp @ Message::FoundNode(_))
-				| Ok(ref resp @ Message::FoundValue(_)) => {
+				| Ok(ref resp @ Message::FoundValue(_))
+				| Ok(ref resp @ Message::Conflict(_))
+				| Ok(ref resp @ Message::Stored(_))
+				| Ok(ref resp @ Message::Error(_)) => {
 					let key = (src, *resp.cookie().unwrap());
 					let pending = self.pending_requests.lock().unwrap();
 					
@@ -197,6 +328,12 @@ impl Iterator for Server {
 						None => (),
 						Some(tx) => ignore(tx.send(resp.clone())),
 					}
+
+					let subscriptions = self.subscriptions.lock().unwrap();
+					match (*subscriptions).get(&key.1) {
+						None => (),
+						Some(tx) => ignore(tx.unbounded_send((src, resp.clone()))),
+					}
 				},
 			}
 
@@ -0,0 +1,146 @@
+use std::io;
+use std::fs::{self,File};
+use std::io::{Read,Write};
+use std::path::{Path,PathBuf};
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use bincode::{serialize, deserialize, Infinite};
+
+use node::{NodeId, NODEID_BYTELEN};
+use storage::{Backend, Entry};
+
+/// Keeps the entries of each key in a file `<dir>/<hex key>`, so they
+/// survive a restart.
+///
+/// All entries are cached in memory, every change is written through.
+pub struct FileBackend<T> {
+	dir:     PathBuf,
+	entries: HashMap<NodeId, Vec<Entry<T>>>,
+}
+
+impl<T> FileBackend<T>
+	where T: Serialize + DeserializeOwned + Clone + Send
+{
+	pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<FileBackend<T>> {
+		let dir = dir.as_ref().to_path_buf();
+		try!(fs::create_dir_all(&dir));
+
+		let mut entries = HashMap::new();
+
+		for file in try!(fs::read_dir(&dir)) {
+			let path = try!(file).path();
+
+			let key = match path.file_name().and_then(|n| n.to_str()).and_then(decode_key) {
+				None => continue,
+				Some(key) => key,
+			};
+
+			let mut buf = vec![];
+			try!(File::open(&path).and_then(|mut f| f.read_to_end(&mut buf)));
+
+			match deserialize::<Vec<Entry<T>>>(&buf[..]) {
+				Ok(values) => { entries.insert(key, values); },
+				Err(e) => warn!("Ignoring corrupt storage file {:?}: {}", path, e),
+			}
+		}
+
+		info!("Loaded {} keys from {:?}", entries.len(), dir);
+
+		Ok(FileBackend {
+			dir:     dir,
+			entries: entries,
+		})
+	}
+
+	fn write(&self, key: &NodeId, entries: &Vec<Entry<T>>) -> io::Result<()> {
+		let path = self.dir.join(encode_key(key));
+
+		if entries.is_empty() {
+			return match fs::remove_file(&path) {
+				Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+				res => res,
+			};
+		}
+
+		let buf = try!(serialize(entries, Infinite)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+		// write the whole file first, so a crash never leaves half of it behind
+		let tmp = path.with_extension("tmp");
+		try!(File::create(&tmp).and_then(|mut f| f.write_all(&buf[..])));
+		fs::rename(&tmp, &path)
+	}
+}
+
+impl<T> Backend<T> for FileBackend<T>
+	where T: Serialize + DeserializeOwned + Clone + Send
+{
+	fn get(&self, key: &NodeId) -> Vec<Entry<T>> {
+		self.entries.get(key).cloned().unwrap_or(vec![])
+	}
+
+	fn set(&mut self, key: NodeId, entries: Vec<Entry<T>>) {
+		if let Err(e) = self.write(&key, &entries) {
+			warn!("Could not write storage file for key {:?}: {}", encode_key(&key), e);
+		}
+
+		if entries.is_empty() {
+			self.entries.remove(&key);
+		} else {
+			self.entries.insert(key, entries);
+		}
+	}
+
+	fn keys(&self) -> Vec<NodeId> {
+		self.entries.keys().cloned().collect()
+	}
+}
+
+fn encode_key(key: &NodeId) -> String {
+	key.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode_key(name: &str) -> Option<NodeId> {
+	if name.len() != 2*NODEID_BYTELEN || !name.is_ascii() {
+		return None;
+	}
+
+	let mut key = [0u8; NODEID_BYTELEN];
+	for (i, b) in key.iter_mut().enumerate() {
+		*b = match u8::from_str_radix(&name[2*i..2*i+2], 16) {
+			Ok(b) => b,
+			Err(_) => return None,
+		};
+	}
+	Some(key)
+}
+
+#[test]
+fn reload() {
+	use std::env;
+	use rand;
+	use storage::now;
+
+	let dir = env::temp_dir().join(format!("bulletinboard-test-{}", rand::random::<u32>()));
+	let key = [0xab; NODEID_BYTELEN];
+	let entry = Entry {
+		value:     vec![1,2,3],
+		sender:    ("127.0.0.1:1".parse().unwrap(), [0x01; NODEID_BYTELEN]),
+		stored_at: now(),
+	};
+
+	{
+		let mut backend = FileBackend::open(&dir).unwrap();
+		backend.set(key, vec![entry.clone()]);
+		backend.set([0xcd; NODEID_BYTELEN], vec![entry.clone()]);
+		backend.set([0xcd; NODEID_BYTELEN], vec![]);
+	}
+
+	let backend:FileBackend<Vec<u8>> = FileBackend::open(&dir).unwrap();
+	assert_eq!(backend.keys(), vec![key]);
+	assert_eq!(backend.get(&key), vec![entry]);
+
+	fs::remove_dir_all(&dir).unwrap();
+}
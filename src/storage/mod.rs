@@ -0,0 +1,282 @@
+pub mod file;
+
+use std::sync::{Arc,Mutex};
+use std::collections::HashMap;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use std::net::SocketAddr;
+
+use node::NodeId;
+use message::Value;
+
+pub use self::file::FileBackend;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry<T> {
+	pub value:     T,
+	pub sender:    (SocketAddr, NodeId),
+	/// wall-clock time in seconds since UNIX_EPOCH, so it survives restarts
+	pub stored_at: u64,
+}
+
+/// Where an `ExternalStorage` keeps its entries
+pub trait Backend<T>: Send {
+	fn get(&self, key: &NodeId) -> Vec<Entry<T>>;
+	/// Replace all entries of `key` (an empty list removes the key)
+	fn set(&mut self, key: NodeId, entries: Vec<Entry<T>>);
+	fn keys(&self) -> Vec<NodeId>;
+}
+
+pub struct MemoryBackend<T> {
+	entries: HashMap<NodeId, Vec<Entry<T>>>,
+}
+
+impl<T> MemoryBackend<T> {
+	pub fn new() -> MemoryBackend<T> {
+		MemoryBackend {
+			entries: HashMap::new(),
+		}
+	}
+}
+
+impl<T: Clone + Send> Backend<T> for MemoryBackend<T> {
+	fn get(&self, key: &NodeId) -> Vec<Entry<T>> {
+		self.entries.get(key).cloned().unwrap_or(vec![])
+	}
+
+	fn set(&mut self, key: NodeId, entries: Vec<Entry<T>>) {
+		if entries.is_empty() {
+			self.entries.remove(&key);
+		} else {
+			self.entries.insert(key, entries);
+		}
+	}
+
+	fn keys(&self) -> Vec<NodeId> {
+		self.entries.keys().cloned().collect()
+	}
+}
+
+pub fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
+#[allow(non_snake_case)]
+#[derive(Clone)]
+pub struct ExternalStorage<T=Vec<u8>> {
+	backend: Arc<Mutex<Box<Backend<T>>>>,
+	ttl:     Duration,
+}
+
+impl<T: Clone + PartialEq + Send + 'static> ExternalStorage<T> {
+	pub fn new(ttl: Duration) -> ExternalStorage<T> {
+		Self::with_backend(ttl, Box::new(MemoryBackend::new()))
+	}
+
+	/// Expired entries of the backend are dropped right away
+	pub fn with_backend(ttl: Duration, backend: Box<Backend<T>>) -> ExternalStorage<T> {
+		let mut storage = ExternalStorage {
+			backend: Arc::new(Mutex::new(backend)),
+			ttl: ttl,
+		};
+
+		storage.cleanup();
+		storage
+	}
+
+	pub fn put(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: T) {
+		self.cleanup();
+
+		let mut backend = self.backend.lock().unwrap();
+
+		let mut s = backend.get(&key);
+		s.iter()
+			.position(|e| e.value == value || e.sender == sender)
+			.map(|pos| s.remove(pos));
+
+		s.push(Entry {
+			value:     value,
+			sender:    sender,
+			stored_at: now(),
+		});
+
+		backend.set(key, s);
+	}
+
+	/// Drop the value `sender` stored under `key`.
+	/// Returns false if there was none.
+	pub fn remove(&mut self, key: &NodeId, sender: &(SocketAddr, NodeId)) -> bool {
+		self.cleanup();
+
+		let mut backend = self.backend.lock().unwrap();
+
+		let mut s = backend.get(key);
+		let len = s.len();
+		s.retain(|e| e.sender != *sender);
+
+		if s.len() == len {
+			return false;
+		}
+
+		backend.set(*key, s);
+		true
+	}
+
+	pub fn get(&mut self, key: &NodeId) -> Vec<((SocketAddr, NodeId), T)> {
+		self.cleanup();
+
+		let backend = self.backend.lock().unwrap();
+
+		backend.get(key).into_iter()
+			.map(|e| (e.sender, e.value))
+			.collect()
+	}
+
+	fn cleanup(&mut self) {
+		let now = now();
+		let ttl = self.ttl.as_secs();
+		let mut backend = self.backend.lock().unwrap();
+
+		for key in backend.keys() {
+			let values = backend.get(&key);
+			let len = values.len();
+
+			let values:Vec<_> = values.into_iter()
+				.filter(|e| e.stored_at + ttl > now)
+				.collect();
+
+			if values.len() != len {
+				backend.set(key, values);
+			}
+		}
+	}
+}
+
+impl ExternalStorage<Value> {
+	/// Like `put()`, but a signed value replaces all older versions no matter
+	/// who sent them.
+	///
+	/// The value is rejected if a newer version is already stored or if
+	/// `cas` does not match the stored version (`cas == 0` expects that no
+	/// version is stored). In that case the sequence number of the stored
+	/// version is returned, 0 if there is none. Storing the very same version
+	/// again always succeeds, so a retried CAS write is not a conflict.
+	pub fn put_value(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: Value, cas: Option<u64>)
+		-> Result<(), u64>
+	{
+		let seq = match value.seq() {
+			None => {
+				self.put(key, sender, value);
+				return Ok(());
+			},
+			Some(seq) => seq,
+		};
+
+		self.cleanup();
+
+		let mut backend = self.backend.lock().unwrap();
+		let mut s = backend.get(&key);
+
+		let current = s.iter()
+			.filter_map(|e| e.value.seq().map(|old| (old, e.value == value)))
+			.next();
+
+		let res = match current {
+			None if cas.map_or(false, |cas| cas != 0) => Err(0),
+			None => Ok(()),
+			Some((old, true)) if old == seq => Ok(()),
+			Some((old, _)) if cas.map_or(false, |cas| cas != old) => Err(old),
+			Some((old, _)) if old > seq => Err(old),
+			Some((old, is_same)) if old == seq && !is_same => Err(old),
+			Some(_) => Ok(()),
+		};
+
+		if res.is_ok() {
+			s.retain(|e| e.value.seq().is_none());
+			s.push(Entry {
+				value:     value,
+				sender:    sender,
+				stored_at: now(),
+			});
+			backend.set(key, s);
+		}
+
+		res
+	}
+}
+
+#[test]
+fn highest_seq_wins() {
+	use mutable::{Keypair, mutable_key};
+
+	let keypair = Keypair::from_seed(&[0x42; 32]);
+	let key = mutable_key(&keypair.public_key(), &[]);
+	let alice = ("127.0.0.1:1".parse().unwrap(), [0x01; 20]);
+	let bob   = ("127.0.0.1:2".parse().unwrap(), [0x02; 20]);
+
+	let mut storage = ExternalStorage::new(Duration::from_secs(60));
+	assert_eq!(storage.put_value(key, alice, keypair.sign(&[], 2, vec![2]), None), Ok(()));
+	assert_eq!(storage.put_value(key, bob, keypair.sign(&[], 1, vec![1]), None), Err(2));
+	assert_eq!(storage.put_value(key, alice, keypair.sign(&[], 2, vec![2]), None), Ok(()));
+	assert_eq!(storage.put_value(key, bob, keypair.sign(&[], 2, vec![3]), None), Err(2));
+	assert_eq!(storage.put_value(key, bob, keypair.sign(&[], 3, vec![3]), None), Ok(()));
+
+	let values:Vec<Vec<u8>> = storage.get(&key).into_iter().map(|(_, v)| v.data).collect();
+	assert_eq!(values, vec![vec![3]]);
+}
+
+#[test]
+fn compare_and_swap() {
+	use mutable::{Keypair, mutable_key};
+
+	let keypair = Keypair::from_seed(&[0x42; 32]);
+	let key = mutable_key(&keypair.public_key(), &[]);
+	let alice = ("127.0.0.1:1".parse().unwrap(), [0x01; 20]);
+	let bob   = ("127.0.0.1:2".parse().unwrap(), [0x02; 20]);
+
+	let mut storage = ExternalStorage::new(Duration::from_secs(60));
+	// nothing is stored yet, so only cas=0 matches
+	assert_eq!(storage.put_value(key, alice, keypair.sign(&[], 1, vec![1]), Some(4)), Err(0));
+	assert_eq!(storage.put_value(key, alice, keypair.sign(&[], 1, vec![1]), Some(0)), Ok(()));
+
+	// alice and bob both read seq=1 and race for seq=2
+	assert_eq!(storage.put_value(key, alice, keypair.sign(&[], 2, vec![2]), Some(1)), Ok(()));
+	assert_eq!(storage.put_value(key, bob, keypair.sign(&[], 3, vec![3]), Some(1)), Err(2));
+
+	// alice retries because the first reply got lost
+	assert_eq!(storage.put_value(key, alice, keypair.sign(&[], 2, vec![2]), Some(1)), Ok(()));
+
+	let values:Vec<Vec<u8>> = storage.get(&key).into_iter().map(|(_, v)| v.data).collect();
+	assert_eq!(values, vec![vec![2]]);
+}
+
+#[test]
+fn expired_entries_are_dropped() {
+	let key = [0x00; 20];
+	let sender = ("127.0.0.1:1".parse().unwrap(), [0x01; 20]);
+
+	let mut backend = MemoryBackend::new();
+	backend.set(key, vec![
+		Entry { value: vec![1], sender: sender, stored_at: now() - 120 },
+		Entry { value: vec![2], sender: sender, stored_at: now() },
+	]);
+
+	let mut storage = ExternalStorage::with_backend(Duration::from_secs(60), Box::new(backend));
+	assert_eq!(storage.get(&key), vec![(sender, vec![2])]);
+}
+
+#[test]
+fn remove() {
+	let key = [0x00; 20];
+	let alice = ("127.0.0.1:1".parse().unwrap(), [0x01; 20]);
+	let bob   = ("127.0.0.1:2".parse().unwrap(), [0x02; 20]);
+
+	let mut storage = ExternalStorage::new(Duration::from_secs(60));
+	storage.put(key, alice, vec![1]);
+	storage.put(key, bob, vec![2]);
+
+	assert!(storage.remove(&key, &alice));
+	assert!(!storage.remove(&key, &alice));
+	assert_eq!(storage.get(&key), vec![(bob, vec![2])]);
+}
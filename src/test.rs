@@ -3,10 +3,47 @@
 use env_logger;
 
 use node::NODEID_BYTELEN;
+use chunked::MAX_CHUNKED_VALUE_LEN;
+use sealed::AppKey;
 use kademlia::Kademlia;
+use builder::KademliaBuilder;
+use transport::SimulatedNetwork;
 
 use std::thread::{spawn,sleep};
 use std::time::Duration;
+use std::sync::Arc;
+use std::net::SocketAddr;
+
+use tokio_core::reactor::{Core, Handle};
+use futures::Stream;
+
+/// The address of the `i`th node of `simulated_nodes()`, the supernode is 1
+fn simulated_addr(i: usize) -> SocketAddr {
+	format!("10.0.0.{}:1000", i).parse().unwrap()
+}
+
+/// A `SimulatedNetwork` seeded with `seed`, a supernode and two nodes
+/// bootstrapped from it. `configure` adjusts the builder of the `i`th
+/// node, see `simulated_addr()`.
+fn simulated_nodes<F>(handle: &Handle, seed: u32, configure: F)
+	-> (SimulatedNetwork, Kademlia, Kademlia, Kademlia)
+	where F: Fn(usize, KademliaBuilder) -> KademliaBuilder
+{
+	let net = SimulatedNetwork::new(seed);
+
+	let kad_super = configure(1, KademliaBuilder::new())
+		.transport(Arc::new(net.bind(simulated_addr(1)).unwrap()))
+		.create(handle.clone()).unwrap();
+
+	let bootstrap = |i| configure(i, KademliaBuilder::new())
+		.transport(Arc::new(net.bind(simulated_addr(i)).unwrap()))
+		.supernode(simulated_addr(1))
+		.bootstrap(handle.clone()).unwrap();
+	let kad1 = bootstrap(2);
+	let kad2 = bootstrap(3);
+
+	(net, kad_super, kad1, kad2)
+}
 
 #[test]
 fn test() {
@@ -18,10 +55,10 @@ fn test() {
 	let ones = [0xFF; NODEID_BYTELEN];
 
 	let super_addr = ("127.0.0.1", 30000);
-	let kad_super = Kademlia::new_supernode(handle, super_addr, Some(zeros.clone()));
+	let kad_super = Kademlia::new_supernode(handle.clone(), super_addr, Some(zeros.clone())).unwrap();
 
-	let mut kad1 = Kademlia::bootstrap("0.0.0.0:30001", vec![super_addr], Some(ones.clone()));
-	let mut kad2 = Kademlia::bootstrap("0.0.0.0:30002", vec![super_addr], Some(ones.clone()));
+	let mut kad1 = Kademlia::bootstrap(handle.clone(), "0.0.0.0:30001", vec![super_addr], Some(ones.clone())).unwrap();
+	let mut kad2 = Kademlia::bootstrap(handle.clone(), "0.0.0.0:30002", vec![super_addr], Some(ones.clone())).unwrap();
 
 	kad1.put(zeros.clone(), vec![1,2,3]).unwrap();
 	kad2.put(zeros.clone(), vec![4,5,6]).unwrap();
@@ -38,16 +75,18 @@ fn test() {
 #[test]
 fn test_concurrent() {
 	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
 
 	let zeros = [0x00; NODEID_BYTELEN];
 	let zeros1 = zeros.clone();
 	let ones = [0xFF; NODEID_BYTELEN];
 
 	let super_addr = ("127.0.0.1", 40000);
-	let kad_super = Kademlia::new_supernode(super_addr, Some(zeros.clone()));
+	let kad_super = Kademlia::new_supernode(handle.clone(), super_addr, Some(zeros.clone())).unwrap();
 
-	let mut kad1 = Kademlia::bootstrap("0.0.0.0:40001", vec![super_addr], Some(ones.clone()));
-	let kad2 = Kademlia::bootstrap("0.0.0.0:40002", vec![super_addr], Some(ones.clone()));
+	let mut kad1 = Kademlia::bootstrap(handle.clone(), "0.0.0.0:40001", vec![super_addr], Some(ones.clone())).unwrap();
+	let kad2 = Kademlia::bootstrap(handle.clone(), "0.0.0.0:40002", vec![super_addr], Some(ones.clone())).unwrap();
 
 	let mut kad11 = kad1.clone();
 	spawn(move || {
@@ -62,3 +101,165 @@ fn test_concurrent() {
 	let result = kad1.get(ones.clone());
 	assert_eq!(result, vec![vec![4,5,6]]);
 }
+
+
+#[test]
+fn test_simulated() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let (net, kad_super, mut kad1, kad2) = simulated_nodes(&handle, 42, |i, builder| match i {
+		1 => builder.node_id(zeros.clone()),
+		2 => builder.node_id(ones.clone()),
+		_ => builder,
+	});
+	// no loss: a lookup does not retry, so a dropped reply changes the result
+	net.set_latency(Duration::from_millis(5), Duration::from_millis(20));
+
+	kad1.put(zeros.clone(), vec![1,2,3]).unwrap();
+	assert_eq!(kad2.get(zeros.clone()), vec![vec![1,2,3]]);
+
+	// kad2 cannot reach anyone
+	net.partition(vec![simulated_addr(3)]);
+	kad1.put(ones.clone(), vec![4,5,6]).unwrap();
+	assert_eq!(kad2.get(ones.clone()), Vec::<Vec<u8>>::new());
+
+	net.heal();
+	assert_eq!(kad2.get(ones.clone()), vec![vec![4,5,6]]);
+}
+
+#[test]
+fn test_restore_stored_values() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (net, kad_super, mut kad1, kad2) = simulated_nodes(&handle, 7, |_, builder| builder);
+	kad1.store(zeros.clone(), vec![1,2,3], 24*60*60).unwrap();
+
+	let values = kad1.get_stored_values();
+	assert_eq!(values.len(), 1);
+	drop(kad1);
+
+	// restart in a network that never saw the value, so only
+	// restore_stored_values() can bring it there
+	let (net, kad_super, kad2, kad3) = simulated_nodes(&handle, 8, |_, builder| builder);
+	let mut kad1 = KademliaBuilder::new()
+		.transport(Arc::new(net.bind(simulated_addr(4)).unwrap()))
+		.supernode(simulated_addr(1))
+		.bootstrap(handle.clone()).unwrap();
+	assert_eq!(kad_super.get(zeros.clone()), Vec::<Vec<u8>>::new());
+
+	kad1.restore_stored_values(values.clone());
+
+	let restored = kad1.get_stored_values();
+	assert_eq!(restored.len(), 1);
+	assert_eq!((restored[0].key, &restored[0].value), (zeros, &vec![1,2,3]));
+
+	sleep(Duration::from_millis(500));
+	assert_eq!(kad_super.get(zeros), vec![vec![1,2,3]]);
+}
+
+#[test]
+fn test_watch() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (net, kad_super, mut kad1, kad2) = simulated_nodes(&handle, 9, |_, builder| builder);
+
+	let mut watch = kad2.watch(zeros.clone()).wait();
+	sleep(Duration::from_millis(500));
+
+	kad1.put(zeros.clone(), vec![1,2,3]).unwrap();
+	kad1.put(zeros.clone(), vec![1,2,3]).unwrap();
+	kad1.put(zeros.clone(), vec![4,5,6]).unwrap();
+
+	assert_eq!(watch.next(), Some(Ok(vec![1,2,3])));
+	assert_eq!(watch.next(), Some(Ok(vec![4,5,6])));
+}
+
+#[test]
+fn test_verify_ids() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let (net, kad_super, kad_fake, kad1) = simulated_nodes(&handle, 11, |i, builder| match i {
+		1 => builder.id_difficulty(4),
+		// claims an id that is not derived from its key
+		2 => builder.node_id(ones.clone()),
+		_ => builder.verify_ids(true).id_difficulty(4),
+	});
+
+	let nodes = kad1.get_nodes();
+	assert!(nodes.iter().any(|n| n.node_id == kad_super.get_own_id()));
+	assert!(!nodes.iter().any(|n| n.node_id == ones));
+}
+
+#[test]
+fn test_put_large() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (net, kad_super, mut kad1, kad2) = simulated_nodes(&handle, 13, |_, builder| builder);
+
+	let value:Vec<u8> = (0..10*1024).map(|i| i as u8).collect();
+	kad1.put_large(zeros.clone(), value.clone()).unwrap();
+	assert_eq!(kad2.get_large(zeros.clone()).unwrap(), vec![value]);
+
+	assert!(kad1.put_large(zeros.clone(), vec![0; MAX_CHUNKED_VALUE_LEN + 1]).is_err());
+}
+
+#[test]
+fn test_put_sealed() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (net, kad_super, mut kad1, kad2) = simulated_nodes(&handle, 15, |_, builder| builder);
+
+	let app_key = AppKey::derive("mytestapp", b"secret");
+	kad1.put_sealed(app_key.clone(), zeros.clone(), vec![1,2,3]).unwrap();
+	kad1.put(zeros.clone(), vec![4,5,6]).unwrap();
+
+	assert_eq!(kad2.get_sealed(&app_key, zeros.clone()), vec![vec![1,2,3]]);
+	assert_eq!(kad2.get_sealed(&AppKey::derive("mytestapp", b"guess"), zeros.clone()), Vec::<Vec<u8>>::new());
+}
+
+#[test]
+fn test_refresh_buckets() {
+	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	// kad1 only adds nodes that answered its own requests, so it does not
+	// learn of kad2 when kad2 joins
+	let (net, kad_super, kad1, kad2) = simulated_nodes(&handle, 17, |i, builder| match i {
+		2 => builder.verify_ids(true),
+		_ => builder,
+	});
+	assert!(!kad1.get_nodes().iter().any(|n| n.node_id == kad2.get_own_id()));
+
+	kad1.refresh_buckets(Duration::from_secs(0));
+	sleep(Duration::from_millis(500));
+
+	let nodes = kad1.get_nodes();
+	assert!(nodes.iter().any(|n| n.node_id == kad_super.get_own_id()));
+	assert!(nodes.iter().any(|n| n.node_id == kad2.get_own_id()));
+}
@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rand;
+
+use node::compact_addr;
+
+/// Opaque to everybody but the node that issued it
+pub type Token = Vec<u8>;
+
+const TOKEN_BYTELEN: usize = 8;
+/// Other implementations, e.g. Mainline nodes, may use longer tokens
+pub const MAX_TOKEN_LEN: usize = 32;
+const SECRET_BYTELEN: usize = 20;
+
+/// A token stays valid for one to two rotations
+const ROTATE_SECS: u64 = 5*60;
+
+struct Secrets {
+	current:    [u8; SECRET_BYTELEN],
+	previous:   [u8; SECRET_BYTELEN],
+	rotated_at: Instant,
+}
+
+/// Hands out write tokens bound to the requester's address, like
+/// BitTorrent's announce_peer token.
+///
+/// A node only gets a token in `FoundNode`/`FoundValue` responses, so a
+/// valid token in a `Store` proves it can receive at its source address.
+pub struct WriteTokens {
+	secrets: Mutex<Secrets>,
+}
+
+impl WriteTokens {
+	pub fn new() -> WriteTokens {
+		WriteTokens {
+			secrets: Mutex::new(Secrets {
+				current:    random_secret(),
+				previous:   random_secret(),
+				rotated_at: Instant::now(),
+			}),
+		}
+	}
+
+	pub fn issue(&self, addr: &SocketAddr) -> Token {
+		let mut secrets = self.secrets.lock().unwrap();
+		rotate_if_due(&mut secrets);
+
+		token(&secrets.current, addr)
+	}
+
+	pub fn verify(&self, addr: &SocketAddr, token: &[u8]) -> bool {
+		let mut secrets = self.secrets.lock().unwrap();
+		rotate_if_due(&mut secrets);
+
+		token == &self::token(&secrets.current, addr)[..]
+			|| token == &self::token(&secrets.previous, addr)[..]
+	}
+
+	fn rotate(&self) {
+		let mut secrets = self.secrets.lock().unwrap();
+		rotate(&mut secrets);
+	}
+}
+
+/// The write tokens other nodes handed out to us, so we can `Store` on them.
+///
+/// A token is dropped after two rotations, the issuer does not accept it
+/// anymore by then.
+pub struct PeerTokens {
+	tokens: Mutex<HashMap<SocketAddr, (Token, Instant)>>,
+}
+
+impl PeerTokens {
+	pub fn new() -> PeerTokens {
+		PeerTokens {
+			tokens: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn insert(&self, addr: SocketAddr, token: Token) {
+		let mut tokens = self.tokens.lock().unwrap();
+		tokens.retain(|_, &mut (_, t)| !is_expired(t));
+		tokens.insert(addr, (token, Instant::now()));
+	}
+
+	pub fn get(&self, addr: &SocketAddr) -> Option<Token> {
+		let tokens = self.tokens.lock().unwrap();
+		match tokens.get(addr) {
+			Some(&(ref token, t)) if !is_expired(t) => Some(token.clone()),
+			_ => None,
+		}
+	}
+}
+
+fn is_expired(received_at: Instant) -> bool {
+	received_at.elapsed() >= Duration::from_secs(2*ROTATE_SECS)
+}
+
+fn rotate_if_due(secrets: &mut Secrets) {
+	if secrets.rotated_at.elapsed() >= Duration::from_secs(ROTATE_SECS) {
+		rotate(secrets);
+	}
+}
+
+fn rotate(secrets: &mut Secrets) {
+	secrets.previous = secrets.current;
+	secrets.current = random_secret();
+	secrets.rotated_at = Instant::now();
+}
+
+fn random_secret() -> [u8; SECRET_BYTELEN] {
+	let mut secret = [0u8; SECRET_BYTELEN];
+	for b in secret.iter_mut() {
+		*b = rand::random();
+	}
+	secret
+}
+
+fn token(secret: &[u8], addr: &SocketAddr) -> Token {
+	let mut hasher = Sha1::new();
+	hasher.input(secret);
+	hasher.input(&compact_addr(addr));
+
+	let mut output = vec![0x0; hasher.output_bytes()];
+	hasher.result(&mut output[..]);
+
+	output.truncate(TOKEN_BYTELEN);
+	output
+}
+
+#[test]
+fn issue_and_verify() {
+	let tokens = WriteTokens::new();
+	let addr = "10.0.0.1:1234".parse().unwrap();
+	let other = "10.0.0.1:1235".parse().unwrap();
+
+	let token = tokens.issue(&addr);
+	assert!(tokens.verify(&addr, &token));
+	assert!(!tokens.verify(&other, &token));
+	assert!(!tokens.verify(&addr, &[0; TOKEN_BYTELEN]));
+	assert!(!tokens.verify(&addr, &[]));
+
+	// valid until the secret was rotated twice
+	tokens.rotate();
+	assert!(tokens.verify(&addr, &token));
+	tokens.rotate();
+	assert!(!tokens.verify(&addr, &token));
+}
+
+#[test]
+fn peer_tokens_expire() {
+	let tokens = PeerTokens::new();
+	let addr = "10.0.0.1:1234".parse().unwrap();
+	let other = "10.0.0.2:1234".parse().unwrap();
+
+	tokens.insert(addr, vec![1]);
+	assert_eq!(tokens.get(&addr), Some(vec![1]));
+	assert_eq!(tokens.get(&other), None);
+
+	let received_at = Instant::now() - Duration::from_secs(2*ROTATE_SECS);
+	tokens.tokens.lock().unwrap().insert(addr, (vec![1], received_at));
+	assert_eq!(tokens.get(&addr), None);
+
+	// expired tokens are evicted
+	tokens.insert(other, vec![2]);
+	assert_eq!(tokens.tokens.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,29 @@
+pub mod simulated;
+
+use std::io;
+use std::net::{SocketAddr,UdpSocket};
+
+pub use self::simulated::{SimulatedNetwork, SimulatedTransport};
+
+/// A datagram transport the `Server` sends and receives messages through
+///
+/// `recv_from()` blocks until a datagram arrives.
+pub trait Transport: Send + Sync {
+	fn local_addr(&self) -> io::Result<SocketAddr>;
+	fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+	fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl Transport for UdpSocket {
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		UdpSocket::local_addr(self)
+	}
+
+	fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+		UdpSocket::send_to(self, buf, addr)
+	}
+
+	fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+		UdpSocket::recv_from(self, buf)
+	}
+}
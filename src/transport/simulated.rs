@@ -0,0 +1,314 @@
+use std::io;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use transport::Transport;
+
+const EPHEMERAL_PORT: u16 = 49152;
+
+struct Packet {
+	deliver_at: Instant,
+	seq:        u64,
+	src:        SocketAddr,
+	data:       Vec<u8>,
+}
+
+impl PartialEq for Packet {
+	fn eq(&self, other: &Packet) -> bool {
+		self.deliver_at == other.deliver_at && self.seq == other.seq
+	}
+}
+
+impl Eq for Packet {}
+
+impl PartialOrd for Packet {
+	fn partial_cmp(&self, other: &Packet) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Packet {
+	/// reversed, so the BinaryHeap pops the packet that is due first
+	fn cmp(&self, other: &Packet) -> Ordering {
+		(other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+	}
+}
+
+struct Inbox {
+	queue: Mutex<BinaryHeap<Packet>>,
+	cvar:  Condvar,
+}
+
+struct Network {
+	inboxes:    HashMap<SocketAddr, Arc<Inbox>>,
+	latency:    Duration,
+	jitter:     Duration,
+	loss:       f64,
+	partitions: Vec<HashSet<SocketAddr>>,
+	seed:       u32,
+	rngs:       HashMap<(SocketAddr, SocketAddr), XorShiftRng>,
+	seq:        u64,
+	next_port:  u16,
+}
+
+impl Network {
+	fn is_connected(&self, a: &SocketAddr, b: &SocketAddr) -> bool {
+		self.partitions.iter().all(|p| p.contains(a) == p.contains(b))
+	}
+
+	/// Every link has its own generator, so the fate of a packet does not
+	/// depend on how the threads of other nodes interleave
+	fn rng(&mut self, src: SocketAddr, dst: SocketAddr) -> &mut XorShiftRng {
+		let seed = self.seed;
+		self.rngs.entry((src, dst)).or_insert_with(|| {
+			let mut hasher = DefaultHasher::new();
+			(src, dst).hash(&mut hasher);
+			let h = hasher.finish();
+			XorShiftRng::from_seed([seed, h as u32, (h >> 32) as u32, 0x97830e05])
+		})
+	}
+
+	fn delay(&mut self, src: SocketAddr, dst: SocketAddr) -> Duration {
+		let jitter_ms = self.jitter.as_secs() * 1000 + (self.jitter.subsec_nanos() / 1_000_000) as u64;
+		if jitter_ms == 0 {
+			self.latency
+		} else {
+			self.latency + Duration::from_millis(self.rng(src, dst).gen_range(0, jitter_ms + 1))
+		}
+	}
+}
+
+/// An in-process network of `SimulatedTransport`s
+///
+/// Packets can be delayed (`latency`), reordered (`jitter`), dropped (`loss`)
+/// and separated by partitions. All randomness comes from the seed and is
+/// drawn per link, so the n-th packet from one address to another meets
+/// the same fate on every run.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+	network: Arc<Mutex<Network>>,
+}
+
+impl SimulatedNetwork {
+	pub fn new(seed: u32) -> SimulatedNetwork {
+		let network = Network {
+			inboxes:    HashMap::new(),
+			latency:    Duration::from_millis(0),
+			jitter:     Duration::from_millis(0),
+			loss:       0.0,
+			partitions: vec![],
+			seed:       seed,
+			rngs:       HashMap::new(),
+			seq:        0,
+			next_port:  EPHEMERAL_PORT,
+		};
+
+		SimulatedNetwork {
+			network: Arc::new(Mutex::new(network)),
+		}
+	}
+
+	/// Attach a new transport to the network (port 0 picks a free port)
+	pub fn bind(&self, addr: SocketAddr) -> io::Result<SimulatedTransport> {
+		let mut network = self.network.lock().unwrap();
+
+		let mut addr = addr;
+		if addr.port() == 0 {
+			while network.inboxes.keys().any(|a| a.ip() == addr.ip() && a.port() == network.next_port) {
+				network.next_port = network.next_port.wrapping_add(1).max(EPHEMERAL_PORT);
+			}
+			addr.set_port(network.next_port);
+		}
+
+		if network.inboxes.contains_key(&addr) {
+			return Err(io::Error::new(io::ErrorKind::AddrInUse, "address already bound"));
+		}
+
+		let inbox = Arc::new(Inbox {
+			queue: Mutex::new(BinaryHeap::new()),
+			cvar:  Condvar::new(),
+		});
+		network.inboxes.insert(addr, inbox.clone());
+
+		Ok(SimulatedTransport {
+			addr:    addr,
+			inbox:   inbox,
+			network: self.clone(),
+		})
+	}
+
+	/// Every packet is delayed by `latency` plus a random amount up to `jitter`
+	pub fn set_latency(&self, latency: Duration, jitter: Duration) {
+		let mut network = self.network.lock().unwrap();
+		network.latency = latency;
+		network.jitter = jitter;
+	}
+
+	/// Drop packets with probability `loss` (0.0 - 1.0)
+	pub fn set_loss(&self, loss: f64) {
+		self.network.lock().unwrap().loss = loss;
+	}
+
+	/// Cut off `group` from the rest of the network
+	pub fn partition(&self, group: Vec<SocketAddr>) {
+		let mut network = self.network.lock().unwrap();
+		network.partitions.push(group.into_iter().collect());
+	}
+
+	/// Remove all partitions
+	pub fn heal(&self) {
+		self.network.lock().unwrap().partitions.clear();
+	}
+
+	fn deliver(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+		let mut network = self.network.lock().unwrap();
+
+		if !network.is_connected(&src, &dst) {
+			debug!("Partitioned: dropping packet {} -> {}", src, dst);
+			return;
+		}
+
+		let loss = network.loss;
+		if loss > 0.0 && network.rng(src, dst).gen::<f64>() < loss {
+			debug!("Lost packet {} -> {}", src, dst);
+			return;
+		}
+
+		let delay = network.delay(src, dst);
+		network.seq += 1;
+
+		let packet = Packet {
+			deliver_at: Instant::now() + delay,
+			seq:        network.seq,
+			src:        src,
+			data:       data.to_vec(),
+		};
+
+		if let Some(inbox) = network.inboxes.get(&dst) {
+			inbox.queue.lock().unwrap().push(packet);
+			inbox.cvar.notify_all();
+		}
+	}
+}
+
+pub struct SimulatedTransport {
+	addr:    SocketAddr,
+	inbox:   Arc<Inbox>,
+	network: SimulatedNetwork,
+}
+
+impl Transport for SimulatedTransport {
+	fn local_addr(&self) -> io::Result<SocketAddr> {
+		Ok(self.addr)
+	}
+
+	fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+		self.network.deliver(self.addr, addr, buf);
+		Ok(buf.len())
+	}
+
+	fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+		let mut queue = self.inbox.queue.lock().unwrap();
+
+		loop {
+			let now = Instant::now();
+			let due = queue.peek().map(|p| p.deliver_at);
+
+			match due {
+				Some(t) if t <= now => {
+					let packet = queue.pop().unwrap();
+					let len = packet.data.len().min(buf.len());
+					buf[..len].copy_from_slice(&packet.data[..len]);
+
+					return Ok((len, packet.src));
+				},
+				Some(t) => queue = self.inbox.cvar.wait_timeout(queue, t - now).unwrap().0,
+				None    => queue = self.inbox.cvar.wait(queue).unwrap(),
+			}
+		}
+	}
+}
+
+impl Drop for SimulatedTransport {
+	fn drop(&mut self) {
+		let mut network = self.network.network.lock().unwrap();
+		network.inboxes.remove(&self.addr);
+	}
+}
+
+#[cfg(test)]
+fn recv(t: &SimulatedTransport) -> (Vec<u8>, SocketAddr) {
+	let mut buf = [0; 64];
+	let (len, src) = t.recv_from(&mut buf).unwrap();
+	(buf[..len].to_vec(), src)
+}
+
+#[test]
+fn deliver() {
+	let net = SimulatedNetwork::new(1);
+	let a = net.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+	let b = net.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+
+	a.send_to(&[1,2,3], b.local_addr().unwrap()).unwrap();
+	assert_eq!(recv(&b), (vec![1,2,3], a.local_addr().unwrap()));
+
+	assert!(net.bind("10.0.0.1:1000".parse().unwrap()).is_err());
+	let c = net.bind("10.0.0.1:0".parse().unwrap()).unwrap();
+	assert_eq!(c.local_addr().unwrap().port(), EPHEMERAL_PORT);
+}
+
+#[test]
+fn loss() {
+	let net = SimulatedNetwork::new(2);
+	let a = net.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+	let b = net.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+
+	net.set_loss(1.0);
+	a.send_to(&[1], b.local_addr().unwrap()).unwrap();
+
+	net.set_loss(0.0);
+	a.send_to(&[2], b.local_addr().unwrap()).unwrap();
+
+	assert_eq!(recv(&b).0, vec![2]);
+}
+
+#[test]
+fn partition() {
+	let net = SimulatedNetwork::new(3);
+	let a = net.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+	let b = net.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+
+	net.partition(vec![a.local_addr().unwrap()]);
+	a.send_to(&[1], b.local_addr().unwrap()).unwrap();
+
+	net.heal();
+	a.send_to(&[2], b.local_addr().unwrap()).unwrap();
+
+	assert_eq!(recv(&b).0, vec![2]);
+}
+
+#[test]
+fn reorder() {
+	let net = SimulatedNetwork::new(4);
+	let a = net.bind("10.0.0.1:1000".parse().unwrap()).unwrap();
+	let b = net.bind("10.0.0.2:1000".parse().unwrap()).unwrap();
+
+	net.set_latency(Duration::from_millis(1), Duration::from_millis(50));
+	for i in 0..20 {
+		a.send_to(&[i], b.local_addr().unwrap()).unwrap();
+	}
+
+	let received:Vec<u8> = (0..20).map(|_| recv(&b).0[0]).collect();
+	let mut sorted = received.clone();
+	sorted.sort();
+
+	assert_eq!(sorted, (0..20).collect::<Vec<u8>>());
+	assert!(received != sorted);
+}
@@ -0,0 +1,85 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use futures::{Stream, Poll, Async};
+use futures::sync::mpsc::UnboundedReceiver;
+use std::net::SocketAddr;
+
+use node::NodeId;
+use server::Server;
+use message::{Message, Value, Cookie, FoundValue};
+use compressed;
+
+/// How long a value counts as seen. Every node storing the key pushes it
+/// and does so again whenever it is republished (every 5 minutes), but a
+/// value put again after it expired is new.
+const SEEN_SECS: u64 = 10*60;
+
+/// A Stream of new values stored under a key, see `Kademlia::watch()`
+///
+/// The `Listen` is not renewed anymore once the `Watch` is dropped.
+pub struct Watch {
+	key:    NodeId,
+	cookie: Cookie,
+	server: Server,
+	rx:     UnboundedReceiver<(SocketAddr, Message)>,
+	seen:   HashMap<Value, Instant>,
+	queued: VecDeque<Value>,
+}
+
+impl Watch {
+	pub fn new(key: NodeId, cookie: Cookie, server: Server) -> Watch {
+		let rx = server.subscribe(cookie);
+
+		Watch {
+			key:    key,
+			cookie: cookie,
+			server: server,
+			rx:     rx,
+			seen:   HashMap::new(),
+			queued: VecDeque::new(),
+		}
+	}
+}
+
+impl Stream for Watch {
+	type Item = Vec<u8>;
+	type Error = ();
+
+	fn poll(&mut self) -> Poll<Option<Vec<u8>>, ()> {
+		loop {
+			let value = match self.queued.pop_front() {
+				Some(value) => value,
+				None => match try!(self.rx.poll()) {
+					Async::NotReady => return Ok(Async::NotReady),
+					Async::Ready(None) => return Ok(Async::Ready(None)),
+					Async::Ready(Some((_, Message::FoundValue(FoundValue { values, .. })))) => {
+						self.queued.extend(values);
+						continue;
+					},
+					Async::Ready(Some(_)) => continue,
+				},
+			};
+
+			if !value.verify(&self.key) {
+				continue;
+			}
+
+			let now = Instant::now();
+			self.seen.retain(|_, t| now.duration_since(*t) < Duration::from_secs(SEEN_SECS));
+
+			// every push of a value we already saw keeps it seen
+			if self.seen.insert(value.clone(), now).is_none() {
+				if let Some(data) = compressed::open(value) {
+					return Ok(Async::Ready(Some(data)));
+				}
+			}
+		}
+	}
+}
+
+impl Drop for Watch {
+	fn drop(&mut self) {
+		self.server.unsubscribe(&self.cookie);
+	}
+}